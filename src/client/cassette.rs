@@ -0,0 +1,72 @@
+//! Recording and replaying of API responses for deterministic tests.  A cassette is a JSON
+//! file holding the frames of each request in order.  When the file does not exist the
+//! responses of real requests are recorded to it, and once it exists they are replayed from
+//! it without calling the API.  Delete the file to record it again.
+
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::google::response::ContentResponse;
+
+use super::Error;
+
+/// The streamed frames of a single request.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Interaction {
+    frames: Vec<ContentResponse>,
+}
+
+#[derive(Debug)]
+pub(crate) enum Cassette {
+    Record {
+        path: PathBuf,
+        interactions: Vec<Interaction>,
+    },
+    Replay {
+        interactions: VecDeque<Interaction>,
+    },
+}
+
+impl Cassette {
+    /// Replays the cassette at `path` if it exists, or records to it otherwise.
+    pub(crate) fn open(path: &Path) -> Result<Self, Error> {
+        if path.exists() {
+            let interactions = serde_json::from_slice(&std::fs::read(path)?)?;
+            Ok(Cassette::Replay { interactions })
+        } else {
+            Ok(Cassette::Record {
+                path: path.to_path_buf(),
+                interactions: Vec::new(),
+            })
+        }
+    }
+
+    /// Returns the frames of the next recorded request when replaying, or `None` when
+    /// recording.  Requests made once the recording runs out fail with `Error::NotFound`.
+    pub(crate) fn replay(&mut self) -> Result<Option<Vec<ContentResponse>>, Error> {
+        match self {
+            Cassette::Record { .. } => Ok(None),
+            Cassette::Replay { interactions } => interactions
+                .pop_front()
+                .map(|interaction| Some(interaction.frames))
+                .ok_or_else(|| Error::NotFound("Cassette interaction".to_string())),
+        }
+    }
+
+    /// Appends the frames of a request when recording, rewriting the file so the recording
+    /// survives a test which fails part way through.
+    pub(crate) fn record(&mut self, frames: &[ContentResponse]) -> Result<(), Error> {
+        if let Cassette::Record { path, interactions } = self {
+            interactions.push(Interaction {
+                frames: frames.to_vec(),
+            });
+            std::fs::write(path, serde_json::to_vec_pretty(interactions)?)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,62 @@
+//! Lenient parsing of JSON mode responses.  Models sometimes wrap their JSON in markdown
+//! fences or leave trailing commas despite the `application/json` mime type, so these are
+//! repaired before giving up.
+
+/// Strip a surrounding markdown code fence, with or without a language tag.
+pub(crate) fn strip_fences(text: &str) -> &str {
+    let text = text.trim();
+
+    let Some(inner) = text.strip_prefix("```") else {
+        return text;
+    };
+    let inner = inner.strip_suffix("```").unwrap_or(inner);
+
+    // Drop the language tag, e.g. `json`, on the opening line.
+    match inner.split_once('\n') {
+        Some((tag, body)) if !tag.trim_start().starts_with(['{', '[']) => body.trim(),
+        _ => inner.trim(),
+    }
+}
+
+/// Remove commas which directly precede a closing brace or bracket, outside of strings.
+pub(crate) fn remove_trailing_commas(text: &str) -> String {
+    let mut repaired = String::with_capacity(text.len());
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, c) in text.char_indices() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+        } else if c == '"' {
+            in_string = true;
+        } else if c == ',' && text[i + 1..].trim_start().starts_with(['}', ']']) {
+            continue;
+        }
+
+        repaired.push(c);
+    }
+
+    repaired
+}
+
+#[cfg(test)]
+mod test {
+    use super::{remove_trailing_commas, strip_fences};
+
+    #[test]
+    fn repairs_fenced_json_with_trailing_commas() {
+        let text = "```json\n{\"a\": [1, 2,], \"b\": \"x,}\",}\n```";
+
+        let repaired = remove_trailing_commas(strip_fences(text));
+
+        assert_eq!(repaired, "{\"a\": [1, 2], \"b\": \"x,}\"}");
+        assert_eq!(strip_fences("```{\"a\": 1}```"), "{\"a\": 1}");
+        assert_eq!(strip_fences(" {\"a\": 1} "), "{\"a\": 1}");
+    }
+}
@@ -1,47 +1,184 @@
-use std::{path::Path, sync::Arc};
+use std::collections::BTreeMap;
+#[cfg(any(test, feature = "test-util"))]
+use std::collections::VecDeque;
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::Path;
+use std::sync::Arc;
+#[cfg(any(test, feature = "test-util"))]
+use std::sync::Mutex;
+use std::time::Duration;
 
 use base64::prelude::*;
-use enum_iterator::all;
 use file_format::FileFormat;
+use reqwest::{
+    IntoUrl, Method, RequestBuilder,
+    header::{self, HeaderMap},
+};
+#[cfg(feature = "mcp")]
 use rust_mcp_sdk::McpClient;
+use serde::de::DeserializeOwned;
 use serde_json::Value;
 use thiserror::Error;
 
 use crate::google::{
     GoogleModel, GoogleModelVariant,
-    common::{Blob, Content, FileData, FunctionCall, HarmCategory, Part, Role},
+    caches::{CacheInfo, CachedContent, ListCachedContentsResponse},
+    common::{
+        Blob, Content, FileData, FunctionCall, FunctionResponse, Modality, Part, Role,
+        split_text_into_contents,
+    },
+    files::{CreateFileRequest, CreateFileResponse, File},
+    pricing::ModelPricing,
     request::{
-        GenerateContentRequest, GenerationConfig, HarmBlockThreshold, SafetySettings,
-        UpdateGenConfig,
+        CountTokensRequest, DynamicRetrievalConfig, FunctionDeclaration, GenerateContentRequest,
+        GenerationConfig, GoogleSearchRetrieval, HarmBlockThreshold, MediaResolution, Mode,
+        ModelRequest, SafetySettings, Schema, ThinkingConfig, Tool, UpdateGenConfig,
+    },
+    response::{
+        ContentResponse, CountTokensResponse, FinishReason, GroundingMetadata, SafetyRating,
+        UsageMetadata, Web,
     },
-    response::ContentResponse,
 };
 
-const URL_BASE: &str = "https://generativelanguage.googleapis.com/v1beta/models";
+#[cfg(all(any(test, feature = "test-util"), not(target_arch = "wasm32")))]
+mod cassette;
+mod json;
+mod stream;
+
+const URL_HOST: &str = "https://generativelanguage.googleapis.com";
+const API_VERSION: &str = "v1beta";
 const URL_EXTENSION: &str = ":streamGenerateContent";
+const URL_GENERATE: &str = ":generateContent";
+const URL_COUNT_TOKENS: &str = ":countTokens";
+const URL_YOUTUBE: [&str; 2] = ["https://www.youtube.com/", "https://youtu.be/"];
+const USER_AGENT: &str = concat!("google-gemini-rs/", env!("CARGO_PKG_VERSION"));
+/// Inline data over roughly 20 MB is rejected by the API.
+const INLINE_SIZE_LIMIT: usize = 20 * 1024 * 1024;
+const PENALTY_RANGE: std::ops::RangeInclusive<f32> = -2.0..=2.0;
+const CANDIDATE_COUNT_RANGE: std::ops::RangeInclusive<i32> = 1..=8;
+const SUMMARIZE_PROMPT: &str = "Summarize the following text:";
+const CONTINUE_PROMPT: &str = "Continue exactly where you left off, without repeating anything.";
+const COMBINE_PROMPT: &str = "The following are summaries of consecutive parts of a single document.  Combine them into one summary:";
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error(transparent)]
     SerdeJson(#[from] serde_json::Error),
     #[error(transparent)]
-    Reqwest(#[from] reqwest::Error),
+    Reqwest(reqwest::Error),
+    #[error("Request timed out")]
+    Timeout(#[source] reqwest::Error),
+    /// An error returned by the API.  `status` is the RPC status, e.g. `PERMISSION_DENIED`,
+    /// and `reason` the reason of the error details, if any, e.g. `API_KEY_INVALID`.
     #[error("Agent Request")]
-    Request { code: i32, message: String },
+    Request {
+        code: i32,
+        message: String,
+        status: String,
+        reason: Option<String>,
+    },
     #[error(transparent)]
     Io(#[from] std::io::Error),
+    #[cfg(feature = "mcp")]
     #[error(transparent)]
     MpcSdk(#[from] rust_mcp_sdk::error::McpSdkError),
     #[error("{0}")]
     UnsupportedConfig(String),
     #[error("{0}")]
     NotFound(String),
+    #[error(transparent)]
+    Base64(#[from] base64::DecodeError),
+    /// The model attempted a function call which could not be parsed, so the turn has no
+    /// usable output.  The user message is kept, so it may be retried with `regenerate`.
+    #[error("The model made a malformed function call")]
+    MalformedFunctionCall,
+    #[error("Invalid API key: {0}")]
+    InvalidKey(String),
+    #[error("Model not found: {0}")]
+    ModelNotFound(String),
+    /// The history cannot be sent, e.g. because it starts with a model turn.
+    #[error(transparent)]
+    InvalidHistory(crate::google::request::Error),
+    /// The response stream failed after the model had produced output.  A stream cannot be
+    /// resumed, so the output received before the failure is returned along with the error.
+    #[error("The response stream was interrupted")]
+    Interrupted {
+        partial: Box<Responses>,
+        #[source]
+        source: Box<Error>,
+    },
+    #[cfg(feature = "image")]
+    #[error(transparent)]
+    Image(#[from] image::ImageError),
+}
+
+/// HTTP status codes of transient failures which may succeed when retried.
+const RETRYABLE_STATUS: [u16; 3] = [429, 500, 503];
+/// Default number of times a request failing with `500 INTERNAL` before any output is
+/// retried.
+const STREAM_RETRIES: usize = 2;
+
+impl Error {
+    /// Returns true if the error is transient, i.e. timeouts, connection failures and the
+    /// 429, 500 and 503 statuses, so the request may succeed when retried.  Invalid requests,
+    /// authentication failures and parse errors are not retryable.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Timeout(_) => true,
+            Error::Reqwest(error) => {
+                error.is_connect()
+                    || error
+                        .status()
+                        .is_some_and(|status| RETRYABLE_STATUS.contains(&status.as_u16()))
+            }
+            Error::Request { code, .. } => {
+                u16::try_from(*code).is_ok_and(|code| RETRYABLE_STATUS.contains(&code))
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error is an authentication failure, i.e. the 401 and 403 statuses
+    /// or the `API_KEY_INVALID` and `PERMISSION_DENIED` statuses, e.g. for a revoked key or a
+    /// disabled API.  Such errors will not succeed until the user re-authenticates.
+    pub fn is_auth_error(&self) -> bool {
+        match self {
+            Error::InvalidKey(_) => true,
+            Error::Reqwest(error) => error
+                .status()
+                .is_some_and(|status| matches!(status.as_u16(), 401 | 403)),
+            Error::Request {
+                code,
+                status,
+                reason,
+                ..
+            } => {
+                matches!(code, 401 | 403)
+                    || status == "PERMISSION_DENIED"
+                    || status == "UNAUTHENTICATED"
+                    || reason.as_deref() == Some("API_KEY_INVALID")
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(value: reqwest::Error) -> Self {
+        if value.is_timeout() {
+            Error::Timeout(value)
+        } else {
+            Error::Reqwest(value)
+        }
+    }
 }
 
 impl From<&Value> for Error {
     fn from(value: &Value) -> Self {
         let mut code = 0;
         let mut message = String::new();
+        let mut status = String::new();
+        let mut reason = None;
         if let Ok(map) = serde_json::from_value::<serde_json::Map<String, Value>>(value.clone()) {
             if let Some(cd) = map.get("code") {
                 code = serde_json::from_value::<i32>(cd.clone()).unwrap_or(0);
@@ -50,57 +187,306 @@ impl From<&Value> for Error {
                 message = serde_json::from_value::<String>(msg.clone())
                     .unwrap_or_else(|_| "Unknown error".to_string());
             }
+            if let Some(Value::String(st)) = map.get("status") {
+                status = st.clone();
+            }
+            if let Some(Value::Array(details)) = map.get("details") {
+                reason = details
+                    .iter()
+                    .find_map(|detail| detail.get("reason")?.as_str())
+                    .map(str::to_string);
+            }
+        }
+        Error::Request {
+            code,
+            message,
+            status,
+            reason,
+        }
+    }
+}
+
+/// A local Rust function which may be called by the model.  It receives the arguments of
+/// the call as a JSON object and returns the result.
+pub type FunctionHandler = dyn Fn(Value) -> Result<Value, Error> + Send + Sync;
+
+/// The settings the HTTP client is built with, kept so that changing one of them rebuilds
+/// the client without losing the others.
+#[derive(Clone, Debug)]
+struct HttpConfig {
+    #[cfg(not(target_arch = "wasm32"))]
+    pool_max_idle_per_host: usize,
+    #[cfg(not(target_arch = "wasm32"))]
+    pool_idle_timeout: Option<Duration>,
+}
+
+impl Default for HttpConfig {
+    /// The defaults of `reqwest`.
+    fn default() -> Self {
+        HttpConfig {
+            #[cfg(not(target_arch = "wasm32"))]
+            pool_max_idle_per_host: usize::MAX,
+            #[cfg(not(target_arch = "wasm32"))]
+            pool_idle_timeout: Some(Duration::from_secs(90)),
         }
-        Error::Request { code, message }
     }
 }
 
+impl HttpConfig {
+    fn build(&self) -> Result<reqwest::Client, Error> {
+        let builder = reqwest::Client::builder();
+
+        #[cfg(not(target_arch = "wasm32"))]
+        let builder = builder
+            .pool_max_idle_per_host(self.pool_max_idle_per_host)
+            .pool_idle_timeout(self.pool_idle_timeout);
+
+        Ok(builder.build()?)
+    }
+}
+
+/// Whether requests use the streaming or the non-streaming generate endpoint.  Both return
+/// the same `Responses`, but the streaming endpoint delivers text progressively while a
+/// non-streaming response arrives whole, so function calls are never split across chunks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StreamingPolicy {
+    /// Stream, unless the request has tools, which are called more reliably without
+    /// streaming.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
 /// Wrapper struct which stores the HTTP Reqwest client and the request history.  The `send`
 /// methods are used to send text and images without having to manage the history manually.
+///
+/// The `with_*` setters configure the client in place and return `&mut Self`, or a `Result`
+/// of it when the setting is validated, so they may be chained.  Use `clone` where an owned,
+/// configured client is needed.
 #[derive(Clone)]
 pub struct Client {
     client: reqwest::Client,
+    http_config: HttpConfig,
     pub model: GoogleModel,
     key: String,
     request: GenerateContentRequest,
+    #[cfg(feature = "mcp")]
     mcps: Vec<Arc<rust_mcp_sdk::mcp_client::ClientRuntime>>,
+    inline_size_limit: usize,
+    automatic_function_calling: bool,
+    user_agent: String,
+    api_version: String,
+    streaming_policy: StreamingPolicy,
+    usage_total: UsageMetadata,
+    stream_retries: usize,
+    cached_tools: Vec<Tool>,
+    #[cfg(any(test, feature = "test-util"))]
+    mock_responses: Option<Arc<Mutex<VecDeque<ContentResponse>>>>,
+    #[cfg(test)]
+    token_counter: Option<fn(&GenerateContentRequest) -> i32>,
+    #[cfg(all(any(test, feature = "test-util"), not(target_arch = "wasm32")))]
+    cassette: Option<Arc<Mutex<cassette::Cassette>>>,
+    stateless: bool,
+    force_system_instruction: bool,
+    lenient_json: bool,
+    functions: Vec<(FunctionDeclaration, Arc<FunctionHandler>)>,
 }
 
 /// The model may return more than one output since we use streaming.  This wrapper
 /// is used as a helper to consolidate the outputs.
 #[derive(Debug)]
-pub struct Responses(Vec<ContentResponse>);
+pub struct Responses {
+    responses: Vec<ContentResponse>,
+    parse_errors: usize,
+    headers: HeaderMap,
+    lenient_json: bool,
+    /// The offsets of the frames at which the requests merged after the first start.
+    request_starts: Vec<usize>,
+}
 
 impl Responses {
     pub fn inner(&self) -> &[ContentResponse] {
-        &self.0
+        &self.responses
+    }
+
+    /// The number of streamed frames which could not be parsed and were dropped.  A
+    /// non-zero count means the consolidated outputs may be incomplete.
+    pub fn parse_errors(&self) -> usize {
+        self.parse_errors
+    }
+
+    /// The HTTP headers of the final response, e.g. the `x-ratelimit-*` quota headers and
+    /// the server request id, which are useful when tuning throughput or filing tickets.
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// Combine the responses of several requests, e.g. steps of a manually driven tool flow,
+    /// so the accessors see them all.  The headers are those of `other`, and `usage` reports
+    /// the total usage of every request.
+    pub fn merge(mut self, other: Responses) -> Responses {
+        let offset = self.responses.len();
+        self.request_starts.push(offset);
+        self.request_starts
+            .extend(other.request_starts.iter().map(|start| start + offset));
+        self.responses.extend(other.responses);
+        self.parse_errors += other.parse_errors;
+        self.headers = other.headers;
+        self
+    }
+}
+
+impl FromIterator<ContentResponse> for Responses {
+    fn from_iter<T: IntoIterator<Item = ContentResponse>>(iter: T) -> Self {
+        Responses {
+            responses: iter.into_iter().collect(),
+            parse_errors: 0,
+            headers: HeaderMap::new(),
+            lenient_json: false,
+            request_starts: Vec::new(),
+        }
     }
 }
 
 impl Responses {
-    /// Squash multiple text responses into a single string.
+    /// Squash the first candidate's text responses into a single string.  Use `text_of`,
+    /// `candidates_text` or `best_text_by_logprob` to read other candidates.
     pub fn text(&self) -> Option<String> {
-        let mut text = String::new();
-        for content in &self.0 {
+        self.text_of(0)
+    }
+
+    /// Squash the text responses of the candidate with the given index into a single string.
+    pub fn text_of(&self, candidate_index: i32) -> Option<String> {
+        let text: String = self
+            .responses
+            .iter()
+            .flat_map(|r| &r.candidates)
+            .filter(|c| c.index.unwrap_or(0) == candidate_index)
+            .flat_map(|c| &c.content.parts)
+            .filter_map(Part::as_text)
+            .collect();
+
+        if text.is_empty() { None } else { Some(text) }
+    }
+
+    /// The text of the candidate with the highest average log probability, falling back to
+    /// the first candidate when no candidate reports one.
+    pub fn best_text_by_logprob(&self) -> Option<String> {
+        let mut logprobs: BTreeMap<i32, f32> = BTreeMap::new();
+        for candidate in self.responses.iter().flat_map(|r| &r.candidates) {
+            if let Some(avg_logprobs) = candidate.avg_logprobs {
+                logprobs.insert(candidate.index.unwrap_or(0), avg_logprobs);
+            }
+        }
+
+        let best = logprobs
+            .into_iter()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map_or(0, |(index, _)| index);
+
+        self.text_of(best)
+    }
+
+    /// Every part of the first candidate in the order the model produced them, e.g. text
+    /// interleaved with code and its results, for renderers which need the exact sequence.
+    pub fn all_parts(&self) -> Vec<&Part> {
+        self.responses
+            .iter()
+            .flat_map(|r| &r.candidates)
+            .filter(|c| c.index.unwrap_or(0) == 0)
+            .flat_map(|c| &c.content.parts)
+            .collect()
+    }
+
+    /// The first candidate's parts merged into a single model turn, for callers which keep
+    /// the history themselves, e.g. with `Client::generate_once`.
+    pub fn to_content(&self) -> Option<Content> {
+        let mut content = Content::model(
+            self.responses
+                .iter()
+                .flat_map(|r| &r.candidates)
+                .filter(|c| c.index.unwrap_or(0) == 0)
+                .flat_map(|c| c.content.parts.iter().cloned())
+                .collect(),
+        );
+
+        if content.parts.is_empty() {
+            return None;
+        }
+
+        content.coalesce_text();
+
+        Some(content)
+    }
+
+    /// The text of each candidate, keyed by the index of the candidate which produced it.
+    /// Candidates which produced no text have an empty string.
+    pub fn candidates_text(&self) -> BTreeMap<i32, String> {
+        let mut texts: BTreeMap<i32, String> = BTreeMap::new();
+        for content in &self.responses {
             for candidate in &content.candidates {
+                let text = texts.entry(candidate.index.unwrap_or(0)).or_default();
                 for part in &candidate.content.parts {
-                    if let Part::Text(txt) = part {
-                        text += txt
+                    if let Part::Text(part_text) = part {
+                        text.push_str(part_text);
                     }
                 }
             }
         }
-        if text.is_empty() { None } else { Some(text) }
+
+        texts
+    }
+
+    /// Parse the text of the response as JSON, e.g. when a response schema is set.  With
+    /// `Client::with_lenient_json` markdown fences and trailing commas are repaired if the
+    /// text does not parse as is.
+    pub fn parse<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        let text = self.text().unwrap_or_default();
+
+        match serde_json::from_str(&text) {
+            Err(error) if self.lenient_json => {
+                let stripped = json::strip_fences(&text);
+                serde_json::from_str(stripped)
+                    .or_else(|_| serde_json::from_str(&json::remove_trailing_commas(stripped)))
+                    .map_err(|_| error.into())
+            }
+            result => Ok(result?),
+        }
     }
 
-    /// Helper to extract the image mime types and Base64 encoded data.
+    /// Helper to extract the image mime types and Base64 encoded data of the first
+    /// candidate.
     pub fn images(&self) -> Vec<(String, String)> {
-        let mut images = Vec::new();
-        for content in &self.0 {
+        self.images_by_candidate().remove(&0).unwrap_or_default()
+    }
+
+    /// Helper to extract the image mime types and decoded bytes of the first candidate, e.g.
+    /// to write them to files or load them with an image library.
+    pub fn images_decoded(&self) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        self.images()
+            .into_iter()
+            .map(|(mime_type, data)| {
+                let bytes = Blob {
+                    mime_type: mime_type.clone(),
+                    data,
+                }
+                .decode()?;
+                Ok((mime_type, bytes))
+            })
+            .collect()
+    }
+
+    /// Helper to extract the image mime types and Base64 encoded data keyed by the index of
+    /// the candidate which produced them.
+    pub fn images_by_candidate(&self) -> BTreeMap<i32, Vec<(String, String)>> {
+        let mut images: BTreeMap<i32, Vec<(String, String)>> = BTreeMap::new();
+        for content in &self.responses {
             for candidate in &content.candidates {
+                let candidate_images = images.entry(candidate.index.unwrap_or(0)).or_default();
                 for part in &candidate.content.parts {
                     if let Part::InlineData(blob) = part {
-                        images.push((blob.mime_type.clone(), blob.data.clone()));
+                        candidate_images.push((blob.mime_type.clone(), blob.data.clone()));
                     }
                 }
             }
@@ -110,12 +496,212 @@ impl Responses {
     }
 }
 
+impl Responses {
+    /// The function calls the model made, which must be answered with
+    /// `Client::submit_tool_results` when automatic function calling is disabled.
+    pub fn function_calls(&self) -> Vec<FunctionCall> {
+        self.responses
+            .iter()
+            .flat_map(|r| &r.candidates)
+            .flat_map(|c| &c.content.parts)
+            .filter_map(Part::as_function_call)
+            .cloned()
+            .collect()
+    }
+
+    /// Splits the first candidate's text into segments, each paired with the web sources
+    /// which support it according to the grounding metadata.  Text which no source supports
+    /// is returned with an empty source list, so concatenating the segments reproduces
+    /// [`Responses::text`] for a single candidate.
+    pub fn annotated_text(&self) -> Vec<(String, Vec<Web>)> {
+        let candidates = || {
+            self.responses
+                .iter()
+                .flat_map(|r| &r.candidates)
+                .filter(|c| c.index.unwrap_or(0) == 0)
+        };
+        let text: String = candidates()
+            .flat_map(|c| &c.content.parts)
+            .filter_map(Part::as_text)
+            .collect();
+        if text.is_empty() {
+            return Vec::new();
+        }
+        let Some(metadata) = candidates()
+            .filter_map(|c| c.grounding_metadata.as_ref())
+            .next_back()
+        else {
+            return vec![(text, Vec::new())];
+        };
+
+        // Segment offsets are in bytes; clamp them onto char boundaries of the text.
+        let clamp = |index: i32| {
+            let mut index = (index.max(0) as usize).min(text.len());
+            while !text.is_char_boundary(index) {
+                index -= 1;
+            }
+            index
+        };
+        let supports: Vec<_> = metadata
+            .grounding_supports
+            .iter()
+            .map(|s| {
+                let start = clamp(s.segment.start_index);
+                let end = clamp(s.segment.end_index);
+                (start, end, &s.grounding_chunk_indices)
+            })
+            .filter(|(start, end, _)| start < end)
+            .collect();
+        let mut bounds: Vec<usize> = supports
+            .iter()
+            .flat_map(|(start, end, _)| [*start, *end])
+            .chain([0, text.len()])
+            .collect();
+        bounds.sort_unstable();
+        bounds.dedup();
+
+        bounds
+            .windows(2)
+            .map(|w| {
+                let mut sources: Vec<Web> = Vec::new();
+                for (_, _, indices) in supports
+                    .iter()
+                    .filter(|(start, end, _)| *start <= w[0] && w[1] <= *end)
+                {
+                    for index in indices.iter() {
+                        if let Some(chunk) = metadata.grounding_chunks.get(*index as usize)
+                            && !sources.iter().any(|s| s.uri == chunk.web.uri)
+                        {
+                            sources.push(chunk.web.clone());
+                        }
+                    }
+                }
+                (text[w[0]..w[1]].to_string(), sources)
+            })
+            .collect()
+    }
+
+    /// The grounding metadata of the first candidate, reported when it was grounded with
+    /// Google search.
+    pub fn grounding_metadata(&self) -> Option<&GroundingMetadata> {
+        self.responses
+            .iter()
+            .rev()
+            .flat_map(|r| &r.candidates)
+            .filter(|c| c.index.unwrap_or(0) == 0)
+            .find_map(|c| c.grounding_metadata.as_ref())
+    }
+
+    /// The HTML and CSS of the Google search suggestions chip, which Google's terms require
+    /// apps to display alongside responses grounded with Google search.
+    pub fn search_entry_point(&self) -> Option<String> {
+        self.grounding_metadata()?
+            .search_entry_point
+            .as_ref()?
+            .rendered_content
+            .clone()
+    }
+
+    /// Returns true if the first candidate was cut off by the output token limit, in which
+    /// case `Client::continue_generation` may be used to extend it.
+    pub fn was_truncated(&self) -> bool {
+        self.responses
+            .iter()
+            .flat_map(|r| &r.candidates)
+            .filter(|c| c.index.unwrap_or(0) == 0)
+            .any(|c| matches!(c.finish_reason, Some(FinishReason::MaxTokens)))
+    }
+
+    /// The safety ratings of the prompt, i.e. the input, which are reported even when the
+    /// prompt is not blocked.
+    pub fn prompt_safety_ratings(&self) -> Vec<SafetyRating> {
+        self.responses
+            .iter()
+            .filter_map(|r| r.prompt_feedback.as_ref())
+            .map(|feedback| feedback.safety_ratings.clone())
+            .find(|ratings| !ratings.is_empty())
+            .unwrap_or_default()
+    }
+
+    /// The safety ratings of the first candidate, i.e. the output.  Streamed chunks may each
+    /// rate the output so far, so the last reported ratings are returned.
+    pub fn candidate_safety_ratings(&self) -> Vec<SafetyRating> {
+        self.responses
+            .iter()
+            .rev()
+            .flat_map(|r| &r.candidates)
+            .filter(|c| c.index.unwrap_or(0) == 0)
+            .map(|c| c.safety_ratings.clone())
+            .find(|ratings| !ratings.is_empty())
+            .unwrap_or_default()
+    }
+
+    /// The average log probability of the first candidate's tokens, which may be used as a
+    /// confidence signal.  Not every model reports it.
+    pub fn avg_logprobs(&self) -> Option<f32> {
+        self.responses
+            .iter()
+            .rev()
+            .flat_map(|r| &r.candidates)
+            .filter(|c| c.index.unwrap_or(0) == 0)
+            .find_map(|c| c.avg_logprobs)
+    }
+
+    /// The usage metadata of the stream, totalled over every merged request.  Each streamed
+    /// chunk reports the running totals of its request, so the largest of each count reported
+    /// by the chunks of a request is its usage.  Chunks without usage, such as intermediate
+    /// chunks, contribute nothing, and if no chunk reported usage every count is zero.
+    pub fn usage(&self) -> UsageMetadata {
+        let mut total = UsageMetadata::zeroed();
+        let mut start = 0;
+
+        for end in self
+            .request_starts
+            .iter()
+            .copied()
+            .chain([self.responses.len()])
+        {
+            let mut usage = UsageMetadata::default();
+            for reported in self.responses[start..end]
+                .iter()
+                .filter_map(|r| r.usage_metadata.as_ref())
+            {
+                usage.accumulate(reported);
+            }
+
+            total.add(&usage);
+            start = end;
+        }
+
+        total
+    }
+
+    /// The number of prompt tokens served from cached content.
+    pub fn cached_token_count(&self) -> i32 {
+        self.usage().cached_content_token_count.unwrap_or(0)
+    }
+
+    /// Returns true if the request was served, at least in part, from cached content.
+    pub fn cache_hit(&self) -> bool {
+        self.cached_token_count() > 0
+    }
+
+    /// Estimate the cost in USD of this response using the given pricing.  Returns 0.0 if
+    /// the API did not report any usage.
+    pub fn estimate_cost(&self, pricing: &ModelPricing) -> f64 {
+        pricing.estimate(&self.usage())
+    }
+}
+
 impl Client {
     /// Creates a new instance of a Reqwest client.  The client is setup to utilize the given
     /// Google Gemini model.
     pub async fn new(model: &GoogleModel, key: &str) -> Result<Self, Error> {
+        let http_config = HttpConfig::default();
+
         Ok(Client {
-            client: reqwest::Client::new(),
+            client: http_config.build()?,
+            http_config,
             model: model.clone(),
             key: key.to_string(),
             request: GenerateContentRequest {
@@ -127,18 +713,56 @@ impl Client {
                 generation_config: None,
                 cached_content: None,
             },
+            #[cfg(feature = "mcp")]
             mcps: vec![],
+            inline_size_limit: INLINE_SIZE_LIMIT,
+            automatic_function_calling: true,
+            user_agent: USER_AGENT.to_string(),
+            api_version: API_VERSION.to_string(),
+            streaming_policy: StreamingPolicy::default(),
+            usage_total: UsageMetadata::default(),
+            stream_retries: STREAM_RETRIES,
+            cached_tools: Vec::new(),
+            #[cfg(any(test, feature = "test-util"))]
+            mock_responses: None,
+            #[cfg(test)]
+            token_counter: None,
+            #[cfg(all(any(test, feature = "test-util"), not(target_arch = "wasm32")))]
+            cassette: None,
+            stateless: false,
+            force_system_instruction: false,
+            lenient_json: false,
+            functions: vec![],
         })
     }
 
     /// Mutates the client by setting sane default configurations based on the model.
-    pub fn with_defaults(&mut self) -> Self {
-        let safety_settings = all::<HarmCategory>()
-            .collect::<Vec<_>>()
+    pub fn with_defaults(&mut self) -> &mut Self {
+        self.set_defaults(HarmBlockThreshold::default());
+
+        self
+    }
+
+    /// Mutates the client by setting sane default configurations based on the model, using
+    /// the given threshold for every harm category.
+    pub fn with_defaults_threshold(
+        &mut self,
+        threshold: HarmBlockThreshold,
+    ) -> Result<&mut Self, Error> {
+        self.check_threshold(&threshold)?;
+        self.set_defaults(threshold);
+
+        Ok(self)
+    }
+
+    fn set_defaults(&mut self, threshold: HarmBlockThreshold) {
+        let safety_settings = self
+            .model
+            .harm_categories()
             .into_iter()
             .map(|cat| SafetySettings {
                 category: cat,
-                threshold: HarmBlockThreshold::default(),
+                threshold: threshold.clone(),
             })
             .collect();
 
@@ -149,15 +773,36 @@ impl Client {
 
         self.request.safety_settings = safety_settings;
         self.request.generation_config = Some(generation_config);
+    }
+
+    fn check_threshold(&self, threshold: &HarmBlockThreshold) -> Result<(), Error> {
+        if matches!(threshold, HarmBlockThreshold::Off) && !self.model.supports_safety_off() {
+            return Err(Error::UnsupportedConfig(format!(
+                "Model {} does not support the Off safety threshold",
+                self.model
+            )));
+        }
 
-        self.to_owned()
+        Ok(())
     }
 
+    #[cfg(feature = "mcp")]
     pub async fn with_tools_client(
         &mut self,
         mcps: Vec<Arc<rust_mcp_sdk::mcp_client::ClientRuntime>>,
-    ) -> Result<Self, Error> {
-        let mut tools = Vec::new();
+    ) -> Result<&mut Self, Error> {
+        self.with_tools(mcps, Default::default()).await
+    }
+
+    /// Mutate the client by setting the tools of the given MCP clients along with the
+    /// built-in tools and function declarations of the builder.
+    #[cfg(feature = "mcp")]
+    pub async fn with_tools(
+        &mut self,
+        mcps: Vec<Arc<rust_mcp_sdk::mcp_client::ClientRuntime>>,
+        mut builder: crate::google::request::ToolBuilder,
+    ) -> Result<&mut Self, Error> {
+        let mut functions = Vec::new();
 
         if matches!(
             self.model.variant,
@@ -169,25 +814,202 @@ impl Client {
             )));
         }
 
+        self.check_tools_not_cached()?;
+
+        for client in &mcps {
+            functions.push(
+                client
+                    .list_tools(None)
+                    .await?
+                    .tools
+                    .iter()
+                    .map(Into::into)
+                    .collect(),
+            )
+        }
+
+        // Function calls are routed to the MCP client at the index of the tool declaring
+        // the function, so the MCP tools must lead.
+        functions.append(&mut builder.functions);
+        if !self.functions.is_empty() {
+            functions.push(
+                self.functions
+                    .iter()
+                    .map(|(declaration, _)| declaration.clone())
+                    .collect(),
+            );
+        }
+        builder.functions = functions;
+
+        self.request.tools = builder
+            .build()
+            .map_err(|e| Error::UnsupportedConfig(e.to_string()))?;
         self.mcps = mcps;
 
+        Ok(self)
+    }
+
+    /// Shut down the MCP clients given to `with_tools`, releasing their connections.  The
+    /// clients are shared through `Arc`s, so dropping the `Client` does not shut them down.
+    /// Clients which are already shut down are skipped, and every client is shut down even if
+    /// an earlier one fails, with the first error returned.
+    #[cfg(feature = "mcp")]
+    pub async fn shutdown_tools(&self) -> Result<(), Error> {
+        let mut result = Ok(());
+
         for client in &self.mcps {
-            tools.push(client.list_tools(None).await?.tools.into())
+            if client.is_shut_down().await {
+                continue;
+            }
+
+            if let Err(error) = client.shut_down().await
+                && result.is_ok()
+            {
+                result = Err(error.into());
+            }
+        }
+
+        result
+    }
+
+    /// Mutate the client by registering a local Rust function which the model may call,
+    /// without an MCP server.  Registering a function with the same name replaces it.
+    pub fn with_function<F>(
+        &mut self,
+        name: &str,
+        description: &str,
+        parameters: Schema,
+        handler: F,
+    ) -> Result<&mut Self, Error>
+    where
+        F: Fn(Value) -> Result<Value, Error> + Send + Sync + 'static,
+    {
+        if matches!(
+            self.model.variant,
+            GoogleModelVariant::Gemini20FlashExpImageGen
+        ) {
+            return Err(Error::UnsupportedConfig(format!(
+                "Model {} does not support tool calls",
+                self.model
+            )));
+        }
+
+        self.check_tools_not_cached()?;
+
+        if self.request.tools.iter().any(|tool| {
+            tool.google_search.is_some()
+                || tool.google_search_retrieval.is_some()
+                || tool.code_execution.is_some()
+                || tool.url_context.is_some()
+        }) {
+            return Err(Error::UnsupportedConfig(
+                "Function declarations may not be combined with built-in tools".to_string(),
+            ));
+        }
+
+        let declaration = FunctionDeclaration {
+            name: name.to_string(),
+            description: description.to_string(),
+            parameters: Some(parameters),
+            response: None,
+        };
+
+        // Local functions are declared together in a trailing tool so that the tool indices
+        // used to route calls to MCP clients are unaffected.
+        let local = self.request.tools.iter().rposition(|tool| {
+            tool.function_declarations
+                .iter()
+                .any(|f| self.functions.iter().any(|(local, _)| local.name == f.name))
+        });
+
+        self.functions.retain(|(local, _)| local.name != name);
+        self.functions
+            .push((declaration.clone(), Arc::new(handler)));
+
+        match local {
+            Some(index) => {
+                let tool = &mut self.request.tools[index];
+                tool.function_declarations.retain(|f| f.name != name);
+                tool.function_declarations.push(declaration);
+            }
+            None => self.request.tools.push(Tool {
+                function_declarations: vec![declaration],
+                ..Default::default()
+            }),
+        }
+
+        Ok(self)
+    }
+
+    /// Mutate the client by grounding prompts with Google search retrieval when the model's
+    /// confidence that search would help exceeds `threshold`, which must be in `[0.0, 1.0]`.
+    pub fn with_dynamic_retrieval(&mut self, threshold: f32) -> Result<&mut Self, Error> {
+        if !(0.0..=1.0).contains(&threshold) {
+            return Err(Error::UnsupportedConfig(format!(
+                "Dynamic retrieval threshold {threshold} is not in [0.0, 1.0]"
+            )));
+        }
+
+        if matches!(
+            self.model.variant,
+            GoogleModelVariant::Gemini20FlashExpImageGen
+        ) {
+            return Err(Error::UnsupportedConfig(format!(
+                "Model {} does not support tool calls",
+                self.model
+            )));
+        }
+
+        self.check_tools_not_cached()?;
+
+        if self
+            .request
+            .tools
+            .iter()
+            .any(|tool| !tool.function_declarations.is_empty() || tool.google_search.is_some())
+        {
+            return Err(Error::UnsupportedConfig(
+                "Google search retrieval may not be combined with function declarations or Google search"
+                    .to_string(),
+            ));
         }
 
-        self.request.tools = tools;
+        self.request
+            .tools
+            .retain(|tool| tool.google_search_retrieval.is_none());
+        self.request.tools.push(Tool {
+            google_search_retrieval: Some(GoogleSearchRetrieval {
+                dynamic_retrieval_config: DynamicRetrievalConfig {
+                    mode: Mode::ModeDynamic,
+                    dynamic_threshold: threshold,
+                },
+            }),
+            ..Default::default()
+        });
 
-        Ok(self.to_owned())
+        Ok(self)
     }
 
-    /// Mutate the client by setting the specified safety settings.
-    pub fn with_safety(&mut self, safety_settings: &[SafetySettings]) -> Self {
-        self.request.safety_settings = safety_settings.to_vec();
+    /// Mutate the client by setting the specified safety settings.  Settings for harm
+    /// categories the model does not accept are dropped, while the `Off` threshold is
+    /// rejected for models which do not support it.
+    pub fn with_safety(&mut self, safety_settings: &[SafetySettings]) -> Result<&mut Self, Error> {
+        for setting in safety_settings {
+            self.check_threshold(&setting.threshold)?;
+        }
+
+        let categories = self.model.harm_categories();
+
+        self.request.safety_settings = safety_settings
+            .iter()
+            .filter(|setting| categories.contains(&setting.category))
+            .cloned()
+            .collect();
 
-        self.to_owned()
+        Ok(self)
     }
 
-    pub fn update_options(&mut self, updates: &[UpdateGenConfig]) -> Self {
+    pub fn update_options(&mut self, updates: &[UpdateGenConfig]) -> &mut Self {
         let mut gen_config = self.request.clone().generation_config.unwrap_or_default();
 
         for update in updates {
@@ -199,6 +1021,9 @@ impl Client {
                 UpdateGenConfig::ResponseSchema(schema) => {
                     gen_config.response_schema = schema.clone()
                 }
+                UpdateGenConfig::ResponseJsonSchema(schema) => {
+                    gen_config.response_json_schema = schema.clone()
+                }
                 UpdateGenConfig::ResponseModalities(items) => {
                     gen_config.response_modalities = items.clone()
                 }
@@ -239,74 +1064,288 @@ impl Client {
 
         self.request.generation_config = Some(gen_config);
 
-        self.to_owned()
+        self
     }
 
-    /// Mutate the client by setting the specified system instructions.  Some models do
-    /// not support system instructions, so in these cases we front-load the system instructions
-    /// as user text content.
-    pub fn with_instructions(&mut self, system_instruction: &str) -> &mut Self {
-        match self.model.variant {
-            GoogleModelVariant::Gemini20FlashExpImageGen => {
-                // The 2.0 flash experimentation image gen model does not support system instructions
-                // as this time, so we'll front-load the instructions as a user message.
-                let mut contents = vec![Content {
-                    parts: vec![Part::Text(system_instruction.to_string())],
-                    role: Role::User,
-                }];
+    /// Mutate the client by setting the presence penalty.  Values outside of the documented
+    /// range (-2.0..=2.0) are rejected locally rather than by the API.
+    pub fn with_presence_penalty(&mut self, presence_penalty: f32) -> Result<&mut Self, Error> {
+        Self::check_penalty("presence_penalty", presence_penalty)?;
 
-                contents.extend(self.request.contents.clone());
+        Ok(self.update_options(&[UpdateGenConfig::PresencePenalty(Some(presence_penalty))]))
+    }
 
-                self.request.contents = contents;
-            }
-            _ => {
-                self.request.system_instruction = Some(Content {
-                    role: Role::User,
-                    parts: vec![Part::Text(system_instruction.to_string())],
-                });
-            }
-        }
+    /// Mutate the client by setting the frequency penalty.  Values outside of the documented
+    /// range (-2.0..=2.0) are rejected locally rather than by the API.
+    pub fn with_frequency_penalty(&mut self, frequency_penalty: f32) -> Result<&mut Self, Error> {
+        Self::check_penalty("frequency_penalty", frequency_penalty)?;
 
-        self
+        Ok(self.update_options(&[UpdateGenConfig::FrequencyPenalty(Some(frequency_penalty))]))
     }
 
-    pub fn with_options(&mut self, options: &GenerationConfig) -> &mut Self {
-        self.request.generation_config = Some(options.clone());
-        self
-    }
+    /// Mutate the client by setting the media resolution used for image and video inputs.
+    /// Lower resolutions reduce the token cost of media inputs at the expense of detail.
+    /// Only models which accept image or video input honor this setting.
+    pub fn with_media_resolution(
+        &mut self,
+        media_resolution: MediaResolution,
+    ) -> Result<&mut Self, Error> {
+        if !self.model.input.contains(&Modality::Image)
+            && !self.model.input.contains(&Modality::Video)
+        {
+            return Err(Error::UnsupportedConfig(format!(
+                "Model {} does not support image or video input",
+                self.model
+            )));
+        }
+
+        Ok(self.update_options(&[UpdateGenConfig::MediaResolution(Some(media_resolution))]))
+    }
+
+    /// Mutate the client by setting the number of candidates to generate for each message,
+    /// which are read back with `Responses::candidates_text` and `images_by_candidate`.
+    /// Counts outside of 1..=8, or above one for models which only generate a single
+    /// candidate, are rejected locally rather than by the API.
+    pub fn with_candidate_count(&mut self, candidate_count: i32) -> Result<&mut Self, Error> {
+        if !CANDIDATE_COUNT_RANGE.contains(&candidate_count) {
+            return Err(Error::UnsupportedConfig(format!(
+                "candidate_count {candidate_count} is outside of the supported range {CANDIDATE_COUNT_RANGE:?}"
+            )));
+        }
+
+        if candidate_count > 1 && !self.model.supports_multiple_candidates() {
+            return Err(Error::UnsupportedConfig(format!(
+                "Model {} does not support multiple candidates",
+                self.model
+            )));
+        }
+
+        Ok(self.update_options(&[UpdateGenConfig::CandidateCount(Some(candidate_count))]))
+    }
+
+    /// Mutate the client by constraining responses to the given JSON Schema converted to a
+    /// `Schema`, with the response mime type set to `application/json`.  Keywords which
+    /// `Schema` cannot express are dropped, and their paths are returned, e.g. `$ref`; use
+    /// `with_json_schema` to send the JSON Schema as is.
+    pub fn with_response_schema_from_value(
+        &mut self,
+        value: Value,
+    ) -> Result<(&mut Self, Vec<String>), Error> {
+        let (schema, dropped) = Schema::from_value(value)
+            .map_err(|error| Error::UnsupportedConfig(format!("Invalid schema: {error}")))?;
+
+        let client = self.update_options(&[
+            UpdateGenConfig::ResponseMimeType(Some("application/json".to_string())),
+            UpdateGenConfig::ResponseJsonSchema(None),
+            UpdateGenConfig::ResponseSchema(Some(schema)),
+        ]);
+
+        Ok((client, dropped))
+    }
+
+    /// Mutate the client by constraining responses to the given JSON Schema, replacing any
+    /// `response_schema` since the API accepts only one of them.  The response mime type is
+    /// set to `application/json`.
+    pub fn with_json_schema(&mut self, schema: Value) -> &mut Self {
+        self.update_options(&[
+            UpdateGenConfig::ResponseMimeType(Some("application/json".to_string())),
+            UpdateGenConfig::ResponseSchema(None),
+            UpdateGenConfig::ResponseJsonSchema(Some(schema)),
+        ])
+    }
+
+    /// Mutate the client by setting the thinking configuration, where `None` leaves a field
+    /// at the model's default.  The budget is validated against the model: -1 requests a
+    /// dynamic budget, 0 turns thinking off where the model allows it, and any other budget
+    /// must be within `GoogleModel::thinking_budget_range`.
+    pub fn with_thinking(
+        &mut self,
+        include_thoughts: Option<bool>,
+        thinking_budget: Option<i32>,
+    ) -> Result<&mut Self, Error> {
+        let Some(range) = self.model.thinking_budget_range() else {
+            return Err(Error::UnsupportedConfig(format!(
+                "Model {} does not support thinking",
+                self.model
+            )));
+        };
+
+        if let Some(budget) = thinking_budget {
+            let valid = match budget {
+                -1 => true,
+                0 => self.model.supports_thinking_off(),
+                _ => range.contains(&budget),
+            };
+
+            if !valid {
+                return Err(Error::UnsupportedConfig(format!(
+                    "thinking_budget {budget} is not supported by model {}, which accepts {range:?}",
+                    self.model
+                )));
+            }
+        }
+
+        Ok(
+            self.update_options(&[UpdateGenConfig::ThinkingConfig(Some(ThinkingConfig {
+                include_thoughts,
+                thinking_budget,
+            }))]),
+        )
+    }
+
+    fn check_penalty(name: &str, value: f32) -> Result<(), Error> {
+        if !PENALTY_RANGE.contains(&value) {
+            return Err(Error::UnsupportedConfig(format!(
+                "{name} {value} is outside of the supported range {PENALTY_RANGE:?}"
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Mutate the client by always sending instructions given to `with_instructions` in the
+    /// `system_instruction` field, even for models which are not known to support it.  This
+    /// avoids the user turn workaround, which costs tokens and may be followed less closely,
+    /// but the API may reject the request if the model really does not support it.  Must be
+    /// set before calling `with_instructions`.
+    pub fn force_system_instruction(&mut self, force: bool) -> &mut Self {
+        self.force_system_instruction = force;
+        self
+    }
+
+    /// The number of turns leading the history which hold instructions front-loaded by
+    /// `with_instructions`, for models which do not support system instructions.
+    fn instruction_turns(&self) -> usize {
+        let front_loaded = matches!(
+            self.model.variant,
+            GoogleModelVariant::Gemini20FlashExpImageGen
+        ) && !self.force_system_instruction
+            && matches!(
+                &self.request.contents[..],
+                [first, second, ..] if is_user_message(first) && is_user_message(second)
+            );
+
+        usize::from(front_loaded)
+    }
+
+    /// Mutate the client by setting the specified system instructions.  Some models do
+    /// not support system instructions, so in these cases we front-load the system instructions
+    /// as user text content, unless `force_system_instruction` is set.
+    pub fn with_instructions(&mut self, system_instruction: &str) -> &mut Self {
+        match self.model.variant {
+            GoogleModelVariant::Gemini20FlashExpImageGen if !self.force_system_instruction => {
+                // The 2.0 flash experimentation image gen model does not support system instructions
+                // as this time, so we'll front-load the instructions as a user message.
+                let mut contents = vec![Content {
+                    parts: vec![Part::Text(system_instruction.to_string())],
+                    role: Role::User,
+                }];
+
+                contents.extend(self.request.contents.clone());
+
+                self.request.contents = contents;
+            }
+            _ => {
+                self.request.system_instruction = Some(Content {
+                    role: Role::User,
+                    parts: vec![Part::Text(system_instruction.to_string())],
+                });
+            }
+        }
+
+        self
+    }
+
+    /// Merge the set fields of `options` into the generation config, leaving the fields it
+    /// does not set, e.g. those of earlier `with_*` calls, untouched.
+    pub fn with_options(&mut self, options: &GenerationConfig) -> &mut Self {
+        self.request
+            .generation_config
+            .get_or_insert_with(GenerationConfig::default)
+            .merge(options);
+        self
+    }
 
     /// Since we're dealing with streams it is possible (?) for the stream to contain
     /// a mixture of successful responses and errors.  For simplicity we bail on error
     /// and return just the error, while we reconsolidate all successful responses.
+    ///
+    /// A single model turn may be split across many streamed chunks, so the parts of the
+    /// first candidate are accumulated and pushed onto the history as one turn with adjacent
+    /// text fragments merged.  Other candidates are only kept in the returned responses.
     fn merge_response(
         &mut self,
         responses: &[ContentResponse],
     ) -> Result<Vec<ContentResponse>, Error> {
         let mut success = Vec::new();
+        let mut turn: Option<Content> = None;
 
         for response in responses {
             if let Some(error) = &response.error {
                 return Err(error.into());
+            } else if response.candidates.iter().any(|candidate| {
+                matches!(
+                    candidate.finish_reason,
+                    Some(FinishReason::MalformedFunctionCall)
+                )
+            }) {
+                return Err(Error::MalformedFunctionCall);
             } else {
-                for candidate in &response.candidates {
-                    if !candidate.content.parts.is_empty() {
-                        self.request.contents.push(candidate.content.clone());
-                    }
+                // Final chunks may carry only a finish reason or usage metadata.
+                for candidate in response.candidates.iter().filter(|candidate| {
+                    candidate.index.unwrap_or(0) == 0 && !candidate.content.parts.is_empty()
+                }) {
+                    turn.get_or_insert_with(|| Content {
+                        parts: vec![],
+                        role: candidate.content.role.clone(),
+                    })
+                    .parts
+                    .extend(candidate.content.parts.iter().cloned());
                 }
                 success.push(response.clone());
             }
         }
 
+        if let Some(mut turn) = turn {
+            turn.coalesce_text();
+            self.request.contents.push(turn);
+        }
+
         Ok(success)
     }
 
+    /// Runs the function the model called, preferring functions registered with
+    /// `with_function` over MCP tools.
     async fn tool_call(&self, function_call: &FunctionCall) -> Result<Vec<Part>, Error> {
+        if let Some((_, handler)) = self
+            .functions
+            .iter()
+            .find(|(declaration, _)| declaration.name == function_call.name)
+        {
+            let args = Value::Object(function_call.args.clone().unwrap_or_default());
+            let mut response = FunctionResponse::new(function_call.name.clone(), handler(args)?);
+            response.id = function_call.id.clone();
+
+            return Ok(vec![Part::FunctionResponse(response)]);
+        }
+
+        self.mcp_tool_call(function_call).await
+    }
+
+    #[cfg(not(feature = "mcp"))]
+    async fn mcp_tool_call(&self, function_call: &FunctionCall) -> Result<Vec<Part>, Error> {
+        Err(Error::NotFound(format!(
+            "Tool for function call {}",
+            function_call.name
+        )))
+    }
+
+    #[cfg(feature = "mcp")]
+    async fn mcp_tool_call(&self, function_call: &FunctionCall) -> Result<Vec<Part>, Error> {
         let mut parts = vec![];
 
         let index = self
-            .request
-            .tools
-            .iter()
+            .tools()
             .enumerate()
             .find(|(_i, t)| {
                 t.function_declarations
@@ -331,7 +1370,7 @@ impl Client {
             let part = match content {
                 rust_mcp_sdk::schema::ContentBlock::TextContent(text_content) => {
                     Part::FunctionResponse(crate::google::common::FunctionResponse {
-                        id: None,
+                        id: function_call.id.clone(),
                         name: function_call.name.clone(),
                         response: serde_json::from_str::<serde_json::Map<String, Value>>(
                             &serde_json::to_string(text_content)?,
@@ -340,7 +1379,7 @@ impl Client {
                 }
                 rust_mcp_sdk::schema::ContentBlock::ImageContent(image_content) => {
                     Part::FunctionResponse(crate::google::common::FunctionResponse {
-                        id: None,
+                        id: function_call.id.clone(),
                         name: function_call.name.clone(),
                         response: serde_json::from_str::<serde_json::Map<String, Value>>(
                             &serde_json::to_string(image_content)?,
@@ -349,7 +1388,7 @@ impl Client {
                 }
                 rust_mcp_sdk::schema::ContentBlock::AudioContent(audio_content) => {
                     Part::FunctionResponse(crate::google::common::FunctionResponse {
-                        id: None,
+                        id: function_call.id.clone(),
                         name: function_call.name.clone(),
                         response: serde_json::from_str::<serde_json::Map<String, Value>>(
                             &serde_json::to_string(audio_content)?,
@@ -358,7 +1397,7 @@ impl Client {
                 }
                 rust_mcp_sdk::schema::ContentBlock::EmbeddedResource(embedded_resource) => {
                     Part::FunctionResponse(crate::google::common::FunctionResponse {
-                        id: None,
+                        id: function_call.id.clone(),
                         name: function_call.name.clone(),
                         response: serde_json::from_str::<serde_json::Map<String, Value>>(
                             &serde_json::to_string(embedded_resource)?,
@@ -367,7 +1406,7 @@ impl Client {
                 }
                 rust_mcp_sdk::schema::ContentBlock::ResourceLink(resource_link) => {
                     Part::FunctionResponse(crate::google::common::FunctionResponse {
-                        id: None,
+                        id: function_call.id.clone(),
                         name: function_call.name.clone(),
                         response: serde_json::from_str::<serde_json::Map<String, Value>>(
                             &serde_json::to_string(resource_link)?,
@@ -383,12 +1422,18 @@ impl Client {
     }
 
     /// Processes tool requests from the model.  We need to push all results onto the content
-    /// request stack for the history.
+    /// request stack for the history.  Function responses carry the id of the call they
+    /// answer so parallel calls can be matched up by the model.
     async fn process_tools(&mut self, in_responses: &[ContentResponse]) -> Result<bool, Error> {
         let mut fn_calls = Vec::new();
 
         for in_response in in_responses {
-            for in_candidate in &in_response.candidates {
+            // Only the first candidate's turn is kept in the history, so only its calls run.
+            for in_candidate in in_response
+                .candidates
+                .iter()
+                .filter(|candidate| candidate.index.unwrap_or(0) == 0)
+            {
                 for in_part in &in_candidate.content.parts {
                     match in_part {
                         Part::Thought(_)
@@ -407,131 +1452,2523 @@ impl Client {
         }
 
         if !fn_calls.is_empty() {
-            for function_call in &fn_calls {
-                let parts = self.tool_call(function_call).await?;
+            // The API has no dedicated function role: results are returned as a single user
+            // turn holding one function response per call made in the model turn.
+            let mut parts = Vec::new();
 
-                self.request.contents.push(Content {
-                    parts,
-                    role: Role::User,
-                });
+            for function_call in &fn_calls {
+                parts.extend(self.tool_call(function_call).await?);
             }
+
+            self.request.contents.push(Content {
+                parts,
+                role: Role::User,
+            });
             Ok(true)
         } else {
             Ok(false)
         }
     }
 
-    async fn do_post(&mut self) -> Result<Vec<ContentResponse>, Error> {
+    /// Posts the request and merges the streamed frames into the history, passing each to
+    /// `on_frame` as it arrives.  A `500 INTERNAL` error before the model produced any output
+    /// is retried up to the `with_stream_retries` limit, while one after it produced output
+    /// is returned as `Error::Interrupted` with the partial output, since a stream cannot be
+    /// resumed.
+    async fn do_post<F>(
+        &mut self,
+        on_frame: &mut F,
+    ) -> Result<(Vec<ContentResponse>, usize, HeaderMap), Error>
+    where
+        F: FnMut(&ContentResponse),
+    {
+        let mut attempt = 0;
+
+        loop {
+            let (frames, parse_errors, headers) = self.fetch_frames(on_frame).await?;
+
+            let internal = frames.iter().enumerate().find_map(|(position, frame)| {
+                let error = Error::from(frame.error.as_ref()?);
+                matches!(error, Error::Request { code: 500, .. }).then_some((position, error))
+            });
+
+            if let Some((position, source)) = internal {
+                let emitted = frames[..position]
+                    .iter()
+                    .flat_map(|frame| &frame.candidates)
+                    .any(|candidate| !candidate.content.parts.is_empty());
+
+                if !emitted && attempt < self.stream_retries {
+                    attempt += 1;
+                    continue;
+                }
+
+                if emitted {
+                    return Err(Error::Interrupted {
+                        partial: Box::new(Responses {
+                            responses: frames[..position].to_vec(),
+                            parse_errors,
+                            headers,
+                            lenient_json: self.lenient_json,
+                            request_starts: Vec::new(),
+                        }),
+                        source: Box::new(source),
+                    });
+                }
+            }
+
+            return Ok((self.merge_response(&frames)?, parse_errors, headers));
+        }
+    }
+
+    /// Fetches the frames of the request from the cassette, if one is replaying, and
+    /// otherwise from the transport, recording them if a cassette is recording.
+    async fn fetch_frames<F>(
+        &mut self,
+        on_frame: &mut F,
+    ) -> Result<(Vec<ContentResponse>, usize, HeaderMap), Error>
+    where
+        F: FnMut(&ContentResponse),
+    {
+        #[cfg(all(any(test, feature = "test-util"), not(target_arch = "wasm32")))]
+        if let Some(cassette) = &self.cassette {
+            let replayed = cassette
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+                .replay()?;
+
+            if let Some(frames) = replayed {
+                frames.iter().for_each(&mut *on_frame);
+                return Ok((frames, 0, HeaderMap::new()));
+            }
+        }
+
+        let (frames, parse_errors, headers) = self.send_request(on_frame).await?;
+
+        #[cfg(all(any(test, feature = "test-util"), not(target_arch = "wasm32")))]
+        if let Some(cassette) = &self.cassette {
+            cassette
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+                .record(&frames)?;
+        }
+
+        Ok((frames, parse_errors, headers))
+    }
+
+    /// Posts the request and parses the streamed frames, passing each to `on_frame` as it
+    /// arrives.  Frames which fail to parse are counted and skipped, unless no frame could be
+    /// parsed at all.
+    async fn send_request<F>(
+        &mut self,
+        on_frame: &mut F,
+    ) -> Result<(Vec<ContentResponse>, usize, HeaderMap), Error>
+    where
+        F: FnMut(&ContentResponse),
+    {
+        #[cfg(any(test, feature = "test-util"))]
+        if let Some(mock_responses) = &self.mock_responses {
+            let response = mock_responses
+                .lock()
+                .ok()
+                .and_then(|mut mock_responses| mock_responses.pop_front())
+                .ok_or_else(|| Error::NotFound("Mock response".to_string()))?;
+
+            on_frame(&response);
+
+            return Ok((vec![response], 0, HeaderMap::new()));
+        }
+
         let request = self
-            .client
-            .post(self.url())
+            .http_request(Method::POST, self.url())
             .header("Content-Type", "application/json")
             .query(&[("key", &self.key)])
             .json(&self.request);
 
-        let responses = request.send().await?.json::<Vec<ContentResponse>>().await?;
+        #[cfg_attr(target_arch = "wasm32", allow(unused_mut))]
+        let mut response = request.send().await?;
+        let headers = response.headers().clone();
+
+        let mut reader = stream::FrameReader::default();
+        let mut responses = Vec::new();
+        let mut parse_errors = Vec::new();
+        let mut collect = |frames: Vec<Result<ContentResponse, Error>>| {
+            for frame in frames {
+                match frame {
+                    Ok(response) => {
+                        on_frame(&response);
+                        responses.push(response);
+                    }
+                    Err(error) => parse_errors.push(error),
+                }
+            }
+        };
+
+        #[cfg(not(target_arch = "wasm32"))]
+        while let Some(chunk) = response.chunk().await? {
+            collect(reader.push(&chunk));
+        }
+        #[cfg(target_arch = "wasm32")]
+        collect(reader.push(&response.bytes().await?));
+
+        collect(reader.finish());
+
+        if responses.is_empty() && !parse_errors.is_empty() {
+            return Err(parse_errors.remove(0));
+        }
 
-        self.merge_response(&responses)
+        Ok((responses, parse_errors.len(), headers))
     }
 
     async fn post(&mut self) -> Result<Responses, Error> {
-        let mut responses = self.do_post().await?;
+        self.post_with(|_| {}).await
+    }
+
+    /// Posts the message at the end of the history, running tools as needed.  A stateless
+    /// client clears the history before the message, and drops the message and every turn
+    /// which followed it once done, whether or not the request succeeded.
+    async fn post_with<F>(&mut self, on_frame: F) -> Result<Responses, Error>
+    where
+        F: FnMut(&ContentResponse),
+    {
+        if self.stateless {
+            let kept = self.instruction_turns();
+            let message = self.request.contents.len().saturating_sub(1);
+            self.request.contents.drain(kept..message.max(kept));
+        }
+
+        let message = self.request.contents.len().saturating_sub(1);
+
+        let result = match self.request.validate_roles() {
+            Ok(()) => self.post_turns(on_frame).await,
+            Err(error) => Err(Error::InvalidHistory(error)),
+        };
+
+        if self.stateless {
+            self.request.contents.truncate(message);
+        }
+
+        result
+    }
+
+    async fn post_turns<F>(&mut self, mut on_frame: F) -> Result<Responses, Error>
+    where
+        F: FnMut(&ContentResponse),
+    {
+        let (mut responses, mut parse_errors, mut headers) = self.do_post(&mut on_frame).await?;
+        self.record_usage(&responses);
 
         // Process all functions that the model maay be calling and feed the results
-        // back in.
-        while self.process_tools(&responses).await? {
-            responses = self.do_post().await?;
+        // back in.  `do_post` only returns once the stream is complete and merged into the
+        // history, so calls are never acted on part way through a streamed turn.
+        while self.automatic_function_calling && self.process_tools(&responses).await? {
+            let (next, errors, next_headers) = self.do_post(&mut on_frame).await?;
+            self.record_usage(&next);
+            responses = next;
+            parse_errors += errors;
+            headers = next_headers;
         }
 
-        Ok(Responses(responses))
+        Ok(Responses {
+            responses,
+            parse_errors,
+            headers,
+            lenient_json: self.lenient_json,
+            request_starts: Vec::new(),
+        })
     }
 
-    /// Send the given text to the model.  Returns the responses or an error
-    /// message if an error was returned.
-    pub async fn send_text(&mut self, text: &str) -> Result<Responses, Error> {
-        self.request.contents.push(Content {
-            parts: vec![Part::Text(text.to_string())],
-            role: Role::User,
-        });
+    /// Adds the usage of a request, reported by its last frame which has any, to the total.
+    fn record_usage(&mut self, responses: &[ContentResponse]) {
+        if let Some(usage) = responses
+            .iter()
+            .rev()
+            .find_map(|r| r.usage_metadata.as_ref())
+        {
+            self.usage_total.add(usage);
+        }
+    }
 
-        self.post().await
+    /// The token usage of every request the client has made since it was created or
+    /// `reset_usage` was last called, including the follow up requests of tool calls, e.g.
+    /// for per user billing of a whole session.
+    pub fn token_usage_total(&self) -> UsageMetadata {
+        self.usage_total.clone()
     }
 
-    pub async fn send_image(&mut self, blob: &Blob) -> Result<Responses, Error> {
-        self.request.contents.push(Content {
-            parts: vec![Part::InlineData(blob.clone())],
-            role: Role::User,
-        });
+    /// Reset the running total reported by `token_usage_total`.
+    pub fn reset_usage(&mut self) -> &mut Self {
+        self.usage_total = UsageMetadata::default();
+        self
+    }
 
-        self.post().await
+    /// Mutate the client so that it does not accumulate history: each `send_*` call clears
+    /// the history before adding its message, and the message, along with the model's turns
+    /// and any tool results, is dropped once the call returns.  Only instructions
+    /// front-loaded by `with_instructions` are kept.  Automatic function calling still works
+    /// within a call, but `submit_tool_results` has no model turn to answer.
+    pub fn with_stateless(&mut self, stateless: bool) -> &mut Self {
+        self.stateless = stateless;
+        self
     }
 
-    pub async fn send_file_data(&mut self, data: &FileData) -> Result<Responses, Error> {
-        self.request.contents.push(Content {
-            parts: vec![Part::FileData(data.clone())],
-            role: Role::User,
-        });
+    /// Mutate the client so that each request is answered with the next of the given
+    /// responses, in order, instead of calling the API.  Tool calls in the responses are run
+    /// as usual, with each follow up request answered by the next response.  Requests made
+    /// once the responses run out fail with `Error::NotFound`.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn with_mock_responses(&mut self, responses: Vec<ContentResponse>) -> &mut Self {
+        self.mock_responses = Some(Arc::new(Mutex::new(responses.into())));
+        self
+    }
 
-        self.post().await
+    /// Mutate the client so that responses are recorded to, or replayed from, the cassette
+    /// file at `path`.  If the file does not exist the responses of real requests are
+    /// recorded to it, and otherwise they are replayed from it in order without calling the
+    /// API, so tests recorded once with an API key may run without one.  Delete the file to
+    /// record it again.
+    #[cfg(all(any(test, feature = "test-util"), not(target_arch = "wasm32")))]
+    pub fn with_cassette(&mut self, path: impl AsRef<Path>) -> Result<&mut Self, Error> {
+        self.cassette = Some(Arc::new(Mutex::new(cassette::Cassette::open(
+            path.as_ref(),
+        )?)));
+        Ok(self)
     }
 
-    pub async fn send_image_file(
+    /// Mutate the client by setting how many times a request which fails with `500 INTERNAL`
+    /// before the model produced any output is retried, 2 by default.
+    pub fn with_stream_retries(&mut self, retries: usize) -> &mut Self {
+        self.stream_retries = retries;
+        self
+    }
+
+    /// Mutate the client by rebuilding its HTTP client with the given connection pool
+    /// settings, e.g. to keep more connections alive for services making many concurrent
+    /// requests.  Not available on WASM, where the browser manages connections.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn with_pool_config(
         &mut self,
-        message: Option<String>,
-        img: &Path,
+        max_idle_per_host: usize,
+        idle_timeout: Duration,
+    ) -> Result<&mut Self, Error> {
+        self.http_config.pool_max_idle_per_host = max_idle_per_host;
+        self.http_config.pool_idle_timeout = Some(idle_timeout);
+        self.client = self.http_config.build()?;
+        Ok(self)
+    }
+
+    /// Mutate the client by replacing the default `google-gemini-rs/<version>` User-Agent
+    /// header sent with every request.
+    pub fn with_user_agent(&mut self, user_agent: &str) -> &mut Self {
+        self.user_agent = user_agent.to_string();
+        self
+    }
+
+    /// Mutate the client by replacing the `v1beta` API version of every endpoint, e.g. with
+    /// `v1alpha` for features which have not reached `v1beta` yet.
+    pub fn with_api_version(&mut self, version: &str) -> &mut Self {
+        self.api_version = version.to_string();
+        self
+    }
+
+    /// Returns the URL of the API resource `path`, e.g. `cachedContents`, for the API version
+    /// in use.
+    fn api_url(&self, path: &str) -> String {
+        format!("{URL_HOST}/{}/{path}", self.api_version)
+    }
+
+    /// Starts an HTTP request carrying the headers sent with every request.  Responses are
+    /// requested gzip compressed and decompressed transparently, including while streaming.
+    fn http_request(&self, method: Method, url: impl IntoUrl) -> RequestBuilder {
+        self.client
+            .request(method, url)
+            .header(header::USER_AGENT, &self.user_agent)
+            .header(header::ACCEPT, "application/json")
+    }
+
+    /// Mutate the client by enabling or disabling lenient parsing of JSON responses with
+    /// `Responses::parse`, which repairs markdown fences and trailing commas.  Disabled by
+    /// default.
+    pub fn with_lenient_json(&mut self, lenient: bool) -> &mut Self {
+        self.lenient_json = lenient;
+        self
+    }
+
+    /// Mutate the client by enabling or disabling automatic function calling, which is
+    /// enabled by default.  When disabled, responses containing function calls are returned
+    /// as is, and the caller runs the functions and returns their results with
+    /// `submit_tool_results`.
+    pub fn with_automatic_function_calling(&mut self, enabled: bool) -> &mut Self {
+        self.automatic_function_calling = enabled;
+        self
+    }
+
+    /// Mutate the client by choosing when requests are streamed.  By default requests with
+    /// tools are not streamed and all others are, see `StreamingPolicy::Auto`.
+    pub fn with_streaming_policy(&mut self, policy: StreamingPolicy) -> &mut Self {
+        self.streaming_policy = policy;
+        self
+    }
+
+    /// Send the results of the function calls the model made in its last turn and re-post,
+    /// for tools which are run outside of MCP.  Responses without an id are given the id of
+    /// the call to the function of the same name.
+    pub async fn submit_tool_results(
+        &mut self,
+        results: Vec<FunctionResponse>,
     ) -> Result<Responses, Error> {
-        let format = FileFormat::from_file(img)?;
+        let calls: Vec<FunctionCall> = self
+            .request
+            .contents
+            .iter()
+            .rev()
+            .find(|content| matches!(content.role, Role::Model))
+            .map(|content| {
+                content
+                    .parts
+                    .iter()
+                    .filter_map(|part| match part {
+                        Part::FunctionCall(call) => Some(call.clone()),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
 
-        let data = BASE64_URL_SAFE.encode(&tokio::fs::read(img).await?);
+        let parts = results
+            .into_iter()
+            .map(|mut result| {
+                if result.id.is_none() {
+                    result.id = calls
+                        .iter()
+                        .find(|call| call.name == result.name)
+                        .and_then(|call| call.id.clone());
+                }
 
-        self.send_image_bytes(message, format.media_type(), &data)
-            .await
+                Part::FunctionResponse(result)
+            })
+            .collect();
+
+        self.request.contents.push(Content::user(parts));
+
+        self.post().await
     }
 
-    pub async fn send_parts(&mut self, parts: &[Part]) -> Result<Responses, Error> {
+    /// Send the given text to the model.  Returns the responses or an error
+    /// message if an error was returned.
+    pub async fn send_text(&mut self, text: &str) -> Result<Responses, Error> {
         self.request.contents.push(Content {
-            parts: parts.to_vec(),
+            parts: vec![Part::Text(text.to_string())],
             role: Role::User,
         });
 
         self.post().await
     }
 
-    /// Send the given image to the model.  This must be a UTF-8 Base64 encoded
-    /// string which is required by the Google API.  Optional text may be sent with
-    /// the image to create a single consolidated message.  Returns the responses
-    /// or an error message if an error was returned.
-    pub async fn send_image_bytes(
+    /// Send a text message like `send_text` with `config` merged over the client's generation
+    /// config for this call only, e.g. to ask one question at a higher temperature.  The
+    /// client's config is restored afterwards, whether or not the request succeeded.
+    pub async fn send_text_with_config(
         &mut self,
-        message: Option<String>,
-        mime_type: &str,
-        data: &str,
+        text: &str,
+        config: &GenerationConfig,
     ) -> Result<Responses, Error> {
-        let mut parts = Vec::new();
+        let previous = self.request.generation_config.clone();
+        self.with_options(config);
 
-        if let Some(message) = message {
-            parts.push(Part::Text(message.to_string()));
+        let result = self.send_text(text).await;
+
+        self.request.generation_config = previous;
+
+        result
+    }
+
+    /// Send a text message like `send_text`, also returning the index into `history` of the
+    /// model's final turn, e.g. to later `rewind_to` it.  Stateless clients keep no history,
+    /// so are rejected before anything is sent.  Fails with `Error::NotFound` if the model
+    /// produced no output to record.
+    pub async fn send_text_indexed(&mut self, text: &str) -> Result<(usize, Responses), Error> {
+        if self.stateless {
+            return Err(Error::UnsupportedConfig(
+                "Stateless clients keep no history to index".to_string(),
+            ));
         }
 
-        parts.push(Part::InlineData(Blob {
-            mime_type: mime_type.to_string(),
-            data: data.to_string(),
-        }));
+        let message = self.request.contents.len();
+
+        let responses = self.send_text(text).await?;
+
+        let index = self
+            .request
+            .contents
+            .iter()
+            .rposition(|content| matches!(content.role, Role::Model))
+            .filter(|index| *index > message)
+            .ok_or_else(|| Error::NotFound("Model turn".to_string()))?;
+
+        Ok((index, responses))
+    }
 
+    /// Send a text message like `send_text`, calling `progress` with the cumulative number
+    /// of characters of text the first candidate has generated as each streamed chunk
+    /// arrives, e.g. to drive a CLI spinner.
+    pub async fn send_text_collecting(
+        &mut self,
+        text: &str,
+        progress: impl Fn(usize),
+    ) -> Result<Responses, Error> {
         self.request.contents.push(Content {
-            parts,
+            parts: vec![Part::Text(text.to_string())],
             role: Role::User,
         });
 
-        self.post().await
+        let mut count = 0;
+        self.post_with(|response| {
+            let chars: usize = response
+                .candidates
+                .iter()
+                .filter(|c| c.index.unwrap_or(0) == 0)
+                .flat_map(|c| &c.content.parts)
+                .filter_map(Part::as_text)
+                .map(|text| text.chars().count())
+                .sum();
+
+            if chars > 0 {
+                count += chars;
+                progress(count);
+            }
+        })
+        .await
     }
 
-    fn url(&self) -> String {
-        format!("{URL_BASE}/{}{URL_EXTENSION}", self.model.name)
+    /// Ask the model to continue its last turn, e.g. when `Responses::was_truncated` reports
+    /// that it was cut off by the output token limit.  The continuation is a new model turn,
+    /// so the full answer is the text of both responses.
+    pub async fn continue_generation(&mut self) -> Result<Responses, Error> {
+        if !self
+            .request
+            .contents
+            .last()
+            .is_some_and(|content| matches!(content.role, Role::Model))
+        {
+            return Err(Error::NotFound("Model turn to continue".to_string()));
+        }
+
+        self.send_text(CONTINUE_PROMPT).await
     }
 
-    /// Returns the entire session content.
-    pub fn history(&self) -> &[Content] {
-        &self.request.contents
+    /// Regenerate the response to the last user message.  The model turns, along with any
+    /// tool calls and results, which followed the last user message are dropped from the
+    /// history and the request is re-sent, re-running tools as needed.  This also sends the
+    /// message left by `edit_last_user_message`.
+    pub async fn regenerate(&mut self) -> Result<Responses, Error> {
+        self.regenerate_with(&[]).await
+    }
+
+    /// Regenerate the response to the last user message after applying the given generation
+    /// config updates, e.g. a different temperature or seed.
+    pub async fn regenerate_with(
+        &mut self,
+        updates: &[UpdateGenConfig],
+    ) -> Result<Responses, Error> {
+        let user_turn = self
+            .request
+            .contents
+            .iter()
+            .rposition(is_user_message)
+            .ok_or_else(|| Error::NotFound("User message to regenerate".to_string()))?;
+
+        self.request.contents.truncate(user_turn + 1);
+        self.update_options(updates);
+
+        self.post().await
+    }
+
+    /// Rewind the history so that the turn at `turn_index` is the latest turn, dropping every
+    /// turn after it.
+    pub fn rewind_to(&mut self, turn_index: usize) -> Result<(), Error> {
+        if turn_index >= self.request.contents.len() {
+            return Err(Error::NotFound(format!("History turn {turn_index}")));
+        }
+
+        self.request.contents.truncate(turn_index + 1);
+
+        Ok(())
+    }
+
+    /// Replace the text of the last user message and drop every turn after it.  Non-text
+    /// parts of the message, such as images, are kept.  Use `regenerate` to send the edited
+    /// message.
+    pub fn edit_last_user_message(&mut self, new_text: &str) -> Result<(), Error> {
+        let user_turn = self
+            .request
+            .contents
+            .iter()
+            .rposition(is_user_message)
+            .ok_or_else(|| Error::NotFound("User message to edit".to_string()))?;
+
+        self.request.contents.truncate(user_turn + 1);
+
+        let content = &mut self.request.contents[user_turn];
+        let text_index = content
+            .parts
+            .iter()
+            .position(|part| matches!(part, Part::Text(_)))
+            .unwrap_or(0);
+
+        content.parts.retain(|part| !matches!(part, Part::Text(_)));
+        content
+            .parts
+            .insert(text_index.min(content.parts.len()), Part::text(new_text));
+
+        Ok(())
+    }
+
+    /// Send the given contents with the client's configuration but without its history.
+    /// The history is neither sent nor modified, so a shared client may be used for one-shot
+    /// requests from many tasks at once.
+    pub async fn generate_once(&self, contents: Vec<Content>) -> Result<Responses, Error> {
+        self.with_contents(contents).post().await
+    }
+
+    /// Send a fully formed conversation with the client's system instruction, configuration
+    /// and tools, and return the model's next turn.  Unlike `generate_once` function calls
+    /// are returned rather than run, so the result is exactly one turn, as evaluation
+    /// harnesses expect.  The client's history is neither sent nor modified.
+    pub async fn complete(&self, contents: &[Content]) -> Result<Responses, Error> {
+        let mut client = self.with_contents(contents.to_vec());
+        client.automatic_function_calling = false;
+
+        client.post().await
+    }
+
+    /// Returns a copy of the client with the given contents in place of the history, without
+    /// cloning the history.
+    fn with_contents(&self, contents: Vec<Content>) -> Client {
+        Client {
+            client: self.client.clone(),
+            http_config: self.http_config.clone(),
+            model: self.model.clone(),
+            key: self.key.clone(),
+            request: GenerateContentRequest {
+                system_instruction: self.request.system_instruction.clone(),
+                contents,
+                tools: self.request.tools.clone(),
+                tool_config: self.request.tool_config.clone(),
+                safety_settings: self.request.safety_settings.clone(),
+                generation_config: self.request.generation_config.clone(),
+                cached_content: self.request.cached_content.clone(),
+            },
+            #[cfg(feature = "mcp")]
+            mcps: self.mcps.clone(),
+            inline_size_limit: self.inline_size_limit,
+            automatic_function_calling: self.automatic_function_calling,
+            user_agent: self.user_agent.clone(),
+            api_version: self.api_version.clone(),
+            streaming_policy: self.streaming_policy,
+            usage_total: UsageMetadata::default(),
+            stream_retries: self.stream_retries,
+            cached_tools: self.cached_tools.clone(),
+            #[cfg(any(test, feature = "test-util"))]
+            mock_responses: self.mock_responses.clone(),
+            #[cfg(test)]
+            token_counter: self.token_counter,
+            #[cfg(all(any(test, feature = "test-util"), not(target_arch = "wasm32")))]
+            cassette: self.cassette.clone(),
+            stateless: self.stateless,
+            force_system_instruction: self.force_system_instruction,
+            lenient_json: self.lenient_json,
+            functions: self.functions.clone(),
+        }
+    }
+
+    /// Check that the API key and model are valid by fetching the model, which has no
+    /// generation cost.  Authentication failures are returned as `Error::InvalidKey` and an
+    /// unknown model as `Error::ModelNotFound`.
+    pub async fn validate(&self) -> Result<(), Error> {
+        let response = self
+            .http_request(
+                Method::GET,
+                self.api_url(&format!("models/{}", self.model.name)),
+            )
+            .query(&[("key", &self.key)])
+            .send()
+            .await?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+
+        let body = response.json::<Value>().await.unwrap_or_default();
+        let error = body
+            .get("error")
+            .map(Error::from)
+            .unwrap_or(Error::Request {
+                code: status.as_u16() as i32,
+                message: status.to_string(),
+                status: String::new(),
+                reason: None,
+            });
+
+        let message = match &error {
+            Error::Request { message, .. } => message.clone(),
+            _ => status.to_string(),
+        };
+
+        match status.as_u16() {
+            _ if error.is_auth_error() => Err(Error::InvalidKey(message)),
+            404 => Err(Error::ModelNotFound(self.model.name.clone())),
+            _ => Err(error),
+        }
+    }
+
+    /// Count the input tokens of the request which would be sent on the next message,
+    /// including the system instruction and tools, using the API.
+    pub async fn count_tokens(&self) -> Result<i32, Error> {
+        self.count_request_tokens(&self.request).await
+    }
+
+    async fn count_request_tokens(&self, request: &GenerateContentRequest) -> Result<i32, Error> {
+        #[cfg(test)]
+        if let Some(token_counter) = self.token_counter {
+            return Ok(token_counter(request));
+        }
+
+        let response = self
+            .http_request(
+                Method::POST,
+                self.api_url(&format!("models/{}{URL_COUNT_TOKENS}", self.model.name)),
+            )
+            .query(&[("key", &self.key)])
+            .json(&CountTokensRequest {
+                generate_content_request: ModelRequest {
+                    model: format!("models/{}", self.model.name),
+                    request: request.clone(),
+                },
+            })
+            .send()
+            .await?
+            .json::<CountTokensResponse>()
+            .await?;
+
+        if let Some(error) = &response.error {
+            return Err(error.into());
+        }
+
+        response
+            .total_tokens
+            .ok_or_else(|| Error::NotFound("Total token count".to_string()))
+    }
+
+    /// Drop the oldest turns of the history until the request fits within the model's input
+    /// token limit less `reserve_output_tokens`.  The system instruction, including one
+    /// front-loaded as the first turn by `with_instructions`, and the most recent user
+    /// message, along with anything after it, are always kept, so the request may still not
+    /// fit.  Returns the number of turns dropped.
+    pub async fn trim_to_fit(&mut self, reserve_output_tokens: i32) -> Result<usize, Error> {
+        let budget = self.model.input_token_limit() - reserve_output_tokens;
+
+        if self.count_tokens().await? <= budget {
+            return Ok(0);
+        }
+
+        // The history may only be cut at a user message, so that model turns or tool results
+        // never lead it.
+        let kept = self.instruction_turns();
+        let cuts: Vec<usize> = self
+            .request
+            .contents
+            .iter()
+            .enumerate()
+            .skip(kept + 1)
+            .filter(|(_, content)| is_user_message(content))
+            .map(|(index, _)| index)
+            .collect();
+
+        if cuts.is_empty() {
+            return Ok(0);
+        }
+
+        // Dropping more turns never adds tokens, so binary search for the fewest dropped turns
+        // which fit, falling back to keeping only the most recent user message onwards.
+        let mut request = self.request.clone();
+        let (mut low, mut high) = (0, cuts.len() - 1);
+
+        while low < high {
+            let middle = (low + high) / 2;
+            request.contents = [
+                &self.request.contents[..kept],
+                &self.request.contents[cuts[middle]..],
+            ]
+            .concat();
+
+            if self.count_request_tokens(&request).await? <= budget {
+                high = middle;
+            } else {
+                low = middle + 1;
+            }
+        }
+
+        self.request.contents.drain(kept..cuts[low]);
+
+        Ok(cuts[low] - kept)
+    }
+
+    /// Summarize text which is too long for a single request.  The text is split into
+    /// chunks of roughly `max_tokens` tokens which are each summarized independently of the
+    /// history, then the summaries are sent as a single message to be combined.  Only the
+    /// combining message and its response are added to the history.
+    pub async fn send_long_text(
+        &mut self,
+        text: &str,
+        max_tokens: usize,
+    ) -> Result<Responses, Error> {
+        let mut summaries = Vec::new();
+
+        for chunk in split_text_into_contents(text, max_tokens) {
+            let mut parts = vec![Part::text(SUMMARIZE_PROMPT)];
+            parts.extend(chunk.parts);
+
+            let summary = self
+                .generate_once(vec![Content::user(parts)])
+                .await?
+                .text()
+                .ok_or_else(|| Error::NotFound("Summary of text chunk".to_string()))?;
+
+            summaries.push(summary);
+        }
+
+        self.send_text(&format!("{COMBINE_PROMPT}\n\n{}", summaries.join("\n\n")))
+            .await
+    }
+
+    pub async fn send_image(&mut self, blob: &Blob) -> Result<Responses, Error> {
+        let part = self.inline_or_upload(blob).await?;
+
+        self.request.contents.push(Content {
+            parts: vec![part],
+            role: Role::User,
+        });
+
+        self.post().await
+    }
+
+    /// Send the given text along with references to previously uploaded files as a single
+    /// message.  The files are not re-uploaded, so the same documents may be referenced
+    /// across turns.
+    pub async fn send_text_with_files(
+        &mut self,
+        text: &str,
+        files: &[FileData],
+    ) -> Result<Responses, Error> {
+        for file in files {
+            self.check_file_data(file)?;
+        }
+
+        let mut parts = vec![Part::text(text)];
+        parts.extend(files.iter().cloned().map(Part::FileData));
+
+        self.request.contents.push(Content::user(parts));
+
+        self.post().await
+    }
+
+    /// Send a reference to a previously uploaded file.  The mime type must be of a modality
+    /// the model accepts and the uri must be a File API or YouTube uri, which is checked
+    /// before sending.
+    pub async fn send_file_data(&mut self, data: &FileData) -> Result<Responses, Error> {
+        self.check_file_data(data)?;
+
+        self.request.contents.push(Content {
+            parts: vec![Part::FileData(data.clone())],
+            role: Role::User,
+        });
+
+        self.post().await
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn send_image_file(
+        &mut self,
+        message: Option<String>,
+        img: &Path,
+    ) -> Result<Responses, Error> {
+        let format = FileFormat::from_file(img)?;
+
+        self.check_input_mime_type(format.media_type())?;
+
+        let bytes = tokio::fs::read(img).await?;
+
+        self.send_image_raw(message, Some(format.media_type()), &bytes)
+            .await
+    }
+
+    /// Ask the model to edit the image at `input` according to `instruction`, returning the
+    /// mime types and Base64 encoded data of the edited images.  Images identical to the input,
+    /// which the model may echo back, are left out.  The model must support image output.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn edit_image(
+        &mut self,
+        instruction: &str,
+        input: &Path,
+    ) -> Result<Vec<(String, String)>, Error> {
+        if !self.model.output.contains(&Modality::Image) {
+            return Err(Error::UnsupportedConfig(format!(
+                "Model {} does not support image output",
+                self.model
+            )));
+        }
+
+        let bytes = tokio::fs::read(input).await?;
+        let format = FileFormat::from_bytes(&bytes);
+
+        self.check_input_mime_type(format.media_type())?;
+
+        let responses = self
+            .send_image_bytes(
+                Some(instruction.to_string()),
+                format.media_type(),
+                &BASE64_URL_SAFE.encode(&bytes),
+            )
+            .await?;
+
+        Ok(responses
+            .images()
+            .into_iter()
+            .filter(|(mime_type, data)| {
+                Blob {
+                    mime_type: mime_type.clone(),
+                    data: data.clone(),
+                }
+                .decode()
+                .map_or(true, |decoded| decoded != bytes)
+            })
+            .collect())
+    }
+
+    /// Send the given raw image bytes to the model, handling the Base64 encoding required
+    /// by the Google API.  When no mime type is given it is detected from the bytes.
+    /// Optional text may be sent with the image to create a single consolidated message.
+    pub async fn send_image_raw(
+        &mut self,
+        message: Option<String>,
+        mime_type: Option<&str>,
+        bytes: &[u8],
+    ) -> Result<Responses, Error> {
+        let detected;
+        let mime_type = match mime_type {
+            Some(mime_type) => mime_type,
+            None => {
+                detected = FileFormat::from_bytes(bytes);
+                detected.media_type()
+            }
+        };
+
+        let data = BASE64_URL_SAFE.encode(bytes);
+
+        self.send_image_bytes(message, mime_type, &data).await
+    }
+
+    /// Send the given image to the model encoded as a PNG.
+    #[cfg(feature = "image")]
+    pub async fn send_dynamic_image(
+        &mut self,
+        message: Option<String>,
+        image: &image::DynamicImage,
+    ) -> Result<Responses, Error> {
+        let mut png = std::io::Cursor::new(Vec::new());
+
+        image.write_to(&mut png, image::ImageFormat::Png)?;
+
+        self.send_image_raw(message, Some("image/png"), png.get_ref())
+            .await
+    }
+
+    /// Send an optional text part followed by the inline blobs and then the file references
+    /// as a single message.  Blobs larger than the inline size limit are uploaded with the
+    /// File API, and the file references are validated as by `send_file_data`.
+    pub async fn send_multimodal(
+        &mut self,
+        text: Option<&str>,
+        blobs: &[Blob],
+        files: &[FileData],
+    ) -> Result<Responses, Error> {
+        for file in files {
+            self.check_file_data(file)?;
+        }
+
+        let mut parts: Vec<Part> = text.map(Part::text).into_iter().collect();
+
+        for blob in blobs {
+            parts.push(self.inline_or_upload(blob).await?);
+        }
+
+        parts.extend(files.iter().cloned().map(Part::FileData));
+
+        self.request.contents.push(Content::user(parts));
+
+        self.post().await
+    }
+
+    /// Send raw 16 bit little endian PCM audio, e.g. from a microphone capture, with an
+    /// optional text message.  The mime type carries the sample rate, e.g.
+    /// `audio/L16;rate=16000`, since the API cannot infer it from headerless samples.
+    pub async fn send_audio_pcm(
+        &mut self,
+        message: Option<&str>,
+        pcm: &[u8],
+        sample_rate: u32,
+    ) -> Result<Responses, Error> {
+        if sample_rate == 0 {
+            return Err(Error::UnsupportedConfig(
+                "PCM audio requires a sample rate".to_string(),
+            ));
+        }
+
+        let blob = Blob {
+            mime_type: format!("audio/L16;rate={sample_rate}"),
+            data: BASE64_URL_SAFE.encode(pcm),
+        };
+        self.check_input_mime_type(&blob.mime_type)?;
+
+        self.send_multimodal(message, &[blob], &[]).await
+    }
+
+    pub async fn send_parts(&mut self, parts: &[Part]) -> Result<Responses, Error> {
+        self.request.contents.push(Content {
+            parts: parts.to_vec(),
+            role: Role::User,
+        });
+
+        self.post().await
+    }
+
+    /// Send the given image to the model.  This must be a UTF-8 Base64 encoded
+    /// string which is required by the Google API.  Optional text may be sent with
+    /// the image to create a single consolidated message.  Images larger than the inline
+    /// size limit are uploaded with the File API and sent by reference.  Returns the
+    /// responses or an error message if an error was returned.
+    pub async fn send_image_bytes(
+        &mut self,
+        message: Option<String>,
+        mime_type: &str,
+        data: &str,
+    ) -> Result<Responses, Error> {
+        let mut parts = Vec::new();
+
+        if let Some(message) = message {
+            parts.push(Part::Text(message.to_string()));
+        }
+
+        parts.push(
+            self.inline_or_upload(&Blob {
+                mime_type: mime_type.to_string(),
+                data: data.to_string(),
+            })
+            .await?,
+        );
+
+        self.request.contents.push(Content {
+            parts,
+            role: Role::User,
+        });
+
+        self.post().await
+    }
+
+    /// Upload the given bytes with the File API so they may be sent by reference with
+    /// `FileData` instead of inline.
+    pub async fn upload_file(
+        &self,
+        mime_type: &str,
+        bytes: &[u8],
+        display_name: Option<&str>,
+    ) -> Result<FileData, Error> {
+        let start = self
+            .http_request(
+                Method::POST,
+                format!("{URL_HOST}/upload/{}/files", self.api_version),
+            )
+            .query(&[("key", &self.key)])
+            .header("X-Goog-Upload-Protocol", "resumable")
+            .header("X-Goog-Upload-Command", "start")
+            .header("X-Goog-Upload-Header-Content-Length", bytes.len())
+            .header("X-Goog-Upload-Header-Content-Type", mime_type)
+            .json(&CreateFileRequest {
+                file: File {
+                    display_name: display_name.map(str::to_string),
+                    ..Default::default()
+                },
+            })
+            .send()
+            .await?;
+
+        let upload_url = match start
+            .headers()
+            .get("x-goog-upload-url")
+            .and_then(|url| url.to_str().ok())
+        {
+            Some(url) => url.to_string(),
+            None => {
+                let response = start.json::<CreateFileResponse>().await?;
+                return Err(response
+                    .error
+                    .as_ref()
+                    .map(Error::from)
+                    .unwrap_or_else(|| Error::NotFound("File upload URL".to_string())));
+            }
+        };
+
+        let response = self
+            .http_request(Method::POST, upload_url)
+            .header("X-Goog-Upload-Offset", 0)
+            .header("X-Goog-Upload-Command", "upload, finalize")
+            .body(bytes.to_vec())
+            .send()
+            .await?
+            .json::<CreateFileResponse>()
+            .await?;
+
+        if let Some(error) = &response.error {
+            return Err(error.into());
+        }
+
+        let file = response
+            .file
+            .ok_or_else(|| Error::NotFound("Uploaded file".to_string()))?;
+
+        Ok(FileData {
+            mime_type: file.mime_type.unwrap_or_else(|| mime_type.to_string()),
+            file_uri: file
+                .uri
+                .ok_or_else(|| Error::NotFound("Uploaded file URI".to_string()))?,
+        })
+    }
+
+    /// Cache the given contents, along with the system instruction and tools of the client,
+    /// for `ttl` so that later requests may refer to them with `with_cached_content`.
+    pub async fn create_cache(
+        &self,
+        contents: Vec<Content>,
+        ttl: Duration,
+    ) -> Result<CachedContent, Error> {
+        let response = self
+            .http_request(Method::POST, self.api_url("cachedContents"))
+            .query(&[("key", &self.key)])
+            .json(&CachedContent {
+                model: Some(format!("models/{}", self.model.name)),
+                system_instruction: self.request.system_instruction.clone(),
+                contents,
+                tools: self.request.tools.clone(),
+                tool_config: self.request.tool_config.clone(),
+                ttl: Some(ttl.into()),
+                ..Default::default()
+            })
+            .send()
+            .await?
+            .json::<CachedContent>()
+            .await?;
+
+        if let Some(error) = &response.error {
+            return Err(error.into());
+        }
+
+        Ok(response)
+    }
+
+    /// Read the named cached content, in the form `cachedContents/{id}`, including its
+    /// `expire_time`.
+    pub async fn get_cache(&self, name: &str) -> Result<CachedContent, Error> {
+        let response = self
+            .http_request(
+                Method::GET,
+                self.api_url(&format!("cachedContents/{}", cache_id(name))),
+            )
+            .query(&[("key", &self.key)])
+            .send()
+            .await?
+            .json::<CachedContent>()
+            .await?;
+
+        if let Some(error) = &response.error {
+            return Err(error.into());
+        }
+
+        Ok(response)
+    }
+
+    /// Extend, or shorten, the lifetime of the named cached content to `new_ttl` from now.
+    pub async fn update_cache(&self, name: &str, new_ttl: Duration) -> Result<(), Error> {
+        let response = self
+            .http_request(
+                Method::PATCH,
+                self.api_url(&format!("cachedContents/{}", cache_id(name))),
+            )
+            .query(&[("key", self.key.as_str()), ("updateMask", "ttl")])
+            .json(&CachedContent {
+                ttl: Some(new_ttl.into()),
+                ..Default::default()
+            })
+            .send()
+            .await?
+            .json::<CachedContent>()
+            .await?;
+
+        if let Some(error) = &response.error {
+            return Err(error.into());
+        }
+
+        Ok(())
+    }
+
+    /// List every cached content, fetching all pages.
+    pub async fn list_caches(&self) -> Result<Vec<CacheInfo>, Error> {
+        let mut caches = Vec::new();
+        let mut page_token = None;
+
+        loop {
+            let (page, next_page_token) =
+                self.list_caches_page(None, page_token.as_deref()).await?;
+            caches.extend(page);
+
+            match next_page_token {
+                Some(token) if !token.is_empty() => page_token = Some(token),
+                _ => return Ok(caches),
+            }
+        }
+    }
+
+    /// List a single page of cached contents, returning it along with the token of the next
+    /// page, if any, for inventories too large to fetch at once.
+    pub async fn list_caches_page(
+        &self,
+        page_size: Option<i32>,
+        page_token: Option<&str>,
+    ) -> Result<(Vec<CacheInfo>, Option<String>), Error> {
+        let mut query = vec![("key", self.key.clone())];
+        if let Some(page_size) = page_size {
+            query.push(("pageSize", page_size.to_string()));
+        }
+        if let Some(page_token) = page_token {
+            query.push(("pageToken", page_token.to_string()));
+        }
+
+        let response = self
+            .http_request(Method::GET, self.api_url("cachedContents"))
+            .query(&query)
+            .send()
+            .await?
+            .json::<ListCachedContentsResponse>()
+            .await?;
+
+        if let Some(error) = &response.error {
+            return Err(error.into());
+        }
+
+        Ok((
+            response
+                .cached_contents
+                .into_iter()
+                .map(CacheInfo::from)
+                .collect(),
+            response.next_page_token,
+        ))
+    }
+
+    /// Mutate the client by referring to the named cached content, in the form
+    /// `cachedContents/{id}`, on subsequent requests.
+    pub fn with_cached_content(&mut self, name: &str) -> &mut Self {
+        self.request.cached_content = Some(name.to_string());
+        self
+    }
+
+    /// Mutate the client by caching its system instruction and tools, which are typically
+    /// large and static, for `ttl` and referring to the cache on subsequent requests, while
+    /// the history is still sent uncached.  The system instruction, tools and tool config are
+    /// cleared from the request since the API rejects them alongside cached content, so tools
+    /// should be configured before calling this; tools can not be added afterwards.  Models
+    /// whose instructions are front-loaded into the history by `with_instructions` are
+    /// rejected, as those instructions would not be cached.
+    pub async fn with_cached_system_and_tools(
+        &mut self,
+        ttl: Duration,
+    ) -> Result<&mut Self, Error> {
+        if matches!(
+            self.model.variant,
+            GoogleModelVariant::Gemini20FlashExpImageGen
+        ) && !self.force_system_instruction
+        {
+            return Err(Error::UnsupportedConfig(format!(
+                "Model {} does not support system instructions to cache",
+                self.model
+            )));
+        }
+
+        let name = self
+            .create_cache(Vec::new(), ttl)
+            .await?
+            .name
+            .ok_or_else(|| Error::NotFound("Cached content name".to_string()))?;
+
+        self.request.system_instruction = None;
+        self.request.tool_config = None;
+        self.cached_tools = std::mem::take(&mut self.request.tools);
+
+        Ok(self.with_cached_content(&name))
+    }
+
+    /// The tools the model may call, the cached tools followed by those sent with each
+    /// request.
+    fn tools(&self) -> impl Iterator<Item = &Tool> {
+        self.cached_tools.iter().chain(&self.request.tools)
+    }
+
+    /// Rejects changes to the tools while cached content is in use, since the API rejects
+    /// tools sent alongside it.
+    fn check_tools_not_cached(&self) -> Result<(), Error> {
+        if self.request.cached_content.is_some() {
+            return Err(Error::UnsupportedConfig(
+                "Tools may not be changed while cached content is in use".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Rejects inputs of a mime type whose modality the model does not accept.
+    fn check_input_mime_type(&self, mime_type: &str) -> Result<(), Error> {
+        let modality = Modality::from_mime_type(mime_type);
+        if !self.model.input.contains(&modality) {
+            return Err(Error::UnsupportedConfig(format!(
+                "Model {} does not support {} input of type {}",
+                self.model, modality, mime_type
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Rejects file references which the model cannot accept, saving a round trip to the API
+    /// for an opaque error.
+    fn check_file_data(&self, data: &FileData) -> Result<(), Error> {
+        self.check_input_mime_type(&data.mime_type)?;
+
+        // File URIs carry the API version they were uploaded with, which may differ from the
+        // version in use.
+        let is_file = data
+            .file_uri
+            .strip_prefix(URL_HOST)
+            .and_then(|path| path.strip_prefix('/')?.split_once('/'))
+            .is_some_and(|(_, path)| path.starts_with("files/"));
+
+        if !is_file && !URL_YOUTUBE.iter().any(|url| data.file_uri.starts_with(url)) {
+            return Err(Error::UnsupportedConfig(format!(
+                "{} is not a File API uri",
+                data.file_uri
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Mutate the client by setting the decoded size, in bytes, above which inline data is
+    /// uploaded with the File API and sent by reference instead.
+    pub fn with_inline_size_limit(&mut self, bytes: usize) -> &mut Self {
+        self.inline_size_limit = bytes;
+        self
+    }
+
+    /// Returns the blob as inline data, or uploads it and returns a file reference if it
+    /// exceeds the inline size limit.
+    async fn inline_or_upload(&self, blob: &Blob) -> Result<Part, Error> {
+        if blob.decoded_len() <= self.inline_size_limit {
+            return Ok(Part::InlineData(blob.clone()));
+        }
+
+        let bytes = blob.decode()?;
+
+        Ok(Part::FileData(
+            self.upload_file(&blob.mime_type, &bytes, None).await?,
+        ))
+    }
+
+    /// Returns the model used by the client.
+    pub fn model(&self) -> &GoogleModel {
+        &self.model
+    }
+
+    /// Returns the modalities the model accepts as input.
+    pub fn input_modalities(&self) -> &[Modality] {
+        &self.model.input
+    }
+
+    /// Returns the modalities the model may produce as output.
+    pub fn output_modalities(&self) -> &[Modality] {
+        &self.model.output
+    }
+
+    fn url(&self) -> String {
+        let streaming = match self.streaming_policy {
+            StreamingPolicy::Auto => self.tools().next().is_none(),
+            StreamingPolicy::Always => true,
+            StreamingPolicy::Never => false,
+        };
+        let method = if streaming {
+            URL_EXTENSION
+        } else {
+            URL_GENERATE
+        };
+
+        self.api_url(&format!("models/{}{method}", self.model.name))
+    }
+
+    /// Returns the entire session content.
+    pub fn history(&self) -> &[Content] {
+        &self.request.contents
+    }
+
+    /// Moves the entire session content out of the client, leaving the history empty.
+    pub fn take_history(&mut self) -> Vec<Content> {
+        std::mem::take(&mut self.request.contents)
+    }
+
+    /// Replaces the entire session content without cloning it.
+    pub fn replace_history(&mut self, history: Vec<Content>) -> &mut Self {
+        self.request.contents = history;
+        self
+    }
+
+    /// Returns the request which will be sent on the next message, including the history.
+    pub fn request(&self) -> &GenerateContentRequest {
+        &self.request
+    }
+
+    /// Returns the request which `send_text` would send for the given text, without sending
+    /// it or adding the text to the history.
+    pub fn dry_run_text(&self, text: &str) -> GenerateContentRequest {
+        let mut request = self.request.clone();
+
+        request.contents.push(Content::user(vec![Part::text(text)]));
+
+        request
+    }
+
+    /// Returns the JSON body of the request which will be sent on the next message.
+    pub fn request_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string(&self.request)?)
+    }
+
+    /// Replaces the request, including the history, with a fully built request.
+    pub fn set_request(&mut self, request: GenerateContentRequest) -> &mut Self {
+        self.request = request;
+        self
+    }
+}
+
+/// Strips the `cachedContents/` prefix from a cache name, so either form may be given.
+fn cache_id(name: &str) -> &str {
+    name.strip_prefix("cachedContents/").unwrap_or(name)
+}
+
+/// Returns true for content authored by the user, as opposed to the model or to the results
+/// of tool calls which are also sent as user content.
+fn is_user_message(content: &Content) -> bool {
+    matches!(content.role, Role::User)
+        && !content
+            .parts
+            .iter()
+            .all(|part| matches!(part, Part::FunctionResponse(_)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use base64::prelude::*;
+    use serde_json::{Value, json};
+
+    use crate::google::{
+        GoogleModel, GoogleModelVariant,
+        common::{
+            Blob, Content, FileData, FunctionCall, HarmCategory, HarmProbability, Part, Role,
+        },
+        request::{GenerationConfig, HarmBlockThreshold, SafetySettings, Schema},
+        response::{
+            Candidate, ContentResponse, FinishReason, PromptFeedBack, SafetyRating, UsageMetadata,
+        },
+    };
+
+    use super::{Client, Error, Responses, StreamingPolicy, stream};
+
+    fn chunk(text: &str) -> ContentResponse {
+        ContentResponse {
+            candidates: vec![Candidate {
+                content: Content {
+                    parts: vec![Part::Text(text.to_string())],
+                    role: Role::Model,
+                },
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    async fn client() -> Client {
+        Client::new(
+            &GoogleModel::new(GoogleModelVariant::Gemini20Flash, None),
+            "",
+        )
+        .await
+        .expect("Expected client.")
+    }
+
+    #[tokio::test]
+    async fn defaults_omit_unsupported_harm_categories() {
+        let mut client = Client::new(
+            &GoogleModel::new(GoogleModelVariant::Gemini25Flash, None),
+            "",
+        )
+        .await
+        .expect("Expected client.");
+        client.with_defaults();
+
+        assert!(!client.request().safety_settings.is_empty());
+        assert!(
+            !client
+                .request()
+                .safety_settings
+                .iter()
+                .any(|setting| setting.category == HarmCategory::HarmCategoryCivicIntegrity)
+        );
+
+        let client = client
+            .with_safety(&[SafetySettings {
+                category: HarmCategory::HarmCategoryCivicIntegrity,
+                threshold: HarmBlockThreshold::BlockNone,
+            }])
+            .expect("Expected safety settings.");
+
+        assert!(client.request().safety_settings.is_empty());
+    }
+
+    #[tokio::test]
+    async fn multi_chunk_stream_is_one_turn() {
+        let mut client = client().await;
+
+        let responses = client
+            .merge_response(&[chunk("Hello"), chunk(", "), chunk("world.")])
+            .expect("Expected merged responses.");
+
+        assert_eq!(responses.len(), 3);
+        assert_eq!(client.history().len(), 1);
+        assert!(matches!(client.history()[0].role, Role::Model));
+        assert_eq!(client.history()[0].parts.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn only_the_first_candidate_is_recorded() {
+        let mut client = client().await;
+        let candidate = |index, text: &str| Candidate {
+            content: Content {
+                parts: vec![Part::Text(text.to_string())],
+                role: Role::Model,
+            },
+            index: Some(index),
+            ..Default::default()
+        };
+
+        let responses = client
+            .merge_response(&[ContentResponse {
+                candidates: vec![candidate(0, "first"), candidate(1, "second")],
+                ..Default::default()
+            }])
+            .expect("Expected merged responses.");
+
+        assert_eq!(responses[0].candidates.len(), 2);
+        assert_eq!(client.history().len(), 1);
+        assert!(matches!(
+            client.history()[0].parts.as_slice(),
+            [Part::Text(text)] if text == "first"
+        ));
+    }
+
+    #[tokio::test]
+    async fn indexed_send_rejects_stateless_clients() {
+        let mut client = client().await;
+        client.with_mock_responses(vec![chunk("Hello."), chunk("Again.")]);
+
+        let (index, _) = client
+            .send_text_indexed("Hi.")
+            .await
+            .expect("Expected response.");
+        assert_eq!(index, 1);
+
+        client.with_stateless(true);
+        assert!(matches!(
+            client.send_text_indexed("Hi.").await,
+            Err(Error::UnsupportedConfig(_))
+        ));
+
+        // Nothing was sent, so the next response is still unused.
+        client.with_stateless(false);
+        let (index, responses) = client
+            .send_text_indexed("Hi.")
+            .await
+            .expect("Expected response.");
+        assert_eq!(index, 3);
+        assert_eq!(responses.text().as_deref(), Some("Again."));
+    }
+
+    #[tokio::test]
+    async fn stateless_sends_clear_the_history() {
+        let mut client = client().await;
+        client
+            .with_stateless(true)
+            .with_mock_responses(vec![chunk("Hello.")])
+            .replace_history(vec![Content::model(vec![Part::text("Earlier.")])]);
+
+        // The leading model turn would be rejected were it sent with the message.
+        let responses = client.send_text("Hi.").await.expect("Expected response.");
+
+        assert_eq!(responses.text().as_deref(), Some("Hello."));
+        assert!(client.history().is_empty());
+    }
+
+    #[tokio::test]
+    async fn trimming_keeps_front_loaded_instructions() {
+        let mut image_gen = Client::new(
+            &GoogleModel::new(GoogleModelVariant::Gemini20FlashExpImageGen, None),
+            "",
+        )
+        .await
+        .expect("Expected client.");
+        image_gen.token_counter = Some(|request| {
+            if request.contents.len() > 4 {
+                i32::MAX
+            } else {
+                0
+            }
+        });
+
+        image_gen
+            .replace_history(vec![
+                Content::user(vec![Part::text("One.")]),
+                Content::model(vec![Part::text("Two.")]),
+                Content::user(vec![Part::text("Three.")]),
+                Content::model(vec![Part::text("Four.")]),
+                Content::user(vec![Part::text("Five.")]),
+            ])
+            .with_instructions("Draw in pastels.");
+
+        assert_eq!(image_gen.trim_to_fit(0).await.expect("Expected trim."), 2);
+        assert_eq!(image_gen.history().len(), 4);
+        assert!(matches!(
+            &image_gen.history()[0].parts[..],
+            [Part::Text(text)] if text == "Draw in pastels."
+        ));
+        assert!(matches!(
+            &image_gen.history()[1].parts[..],
+            [Part::Text(text)] if text == "Three."
+        ));
+    }
+
+    #[tokio::test]
+    async fn edit_last_user_message_drops_later_turns() {
+        let mut client = client().await;
+        client.set_request(crate::google::request::GenerateContentRequest {
+            contents: vec![
+                Content::user(vec![Part::text("Hi.")]),
+                Content::model(vec![Part::text("Hello.")]),
+                Content::user(vec![
+                    Part::inline_data("image/png", ""),
+                    Part::text("What is this?"),
+                ]),
+                Content::model(vec![Part::text("A penguin.")]),
+            ],
+            ..client.request().clone()
+        });
+
+        client
+            .edit_last_user_message("What animal is this?")
+            .expect("Expected user message.");
+
+        assert_eq!(client.history().len(), 3);
+        assert!(matches!(
+            &client.history()[2].parts[..],
+            [Part::InlineData(_), Part::Text(text)] if text == "What animal is this?"
+        ));
+
+        client.rewind_to(0).expect("Expected first turn.");
+
+        assert_eq!(client.history().len(), 1);
+        assert!(client.rewind_to(1).is_err());
+    }
+
+    #[tokio::test]
+    async fn usage_only_chunk_is_retained() {
+        let mut client = client().await;
+
+        let frames = stream::parse_frames(
+            r#"[{"candidates": [{"content": {"parts": [{"text": "Hi."}], "role": "model"}}]},
+{"candidates": [{"finishReason": "STOP"}], "usageMetadata": {"promptTokenCount": 3, "totalTokenCount": 5}}]"#,
+        );
+        let responses = Responses {
+            responses: client
+                .merge_response(
+                    &frames
+                        .into_iter()
+                        .collect::<Result<Vec<_>, _>>()
+                        .expect("Expected frames."),
+                )
+                .expect("Expected merged responses."),
+            parse_errors: 0,
+            headers: Default::default(),
+            lenient_json: false,
+            request_starts: Vec::new(),
+        };
+
+        assert_eq!(responses.inner().len(), 2);
+        assert_eq!(responses.text().as_deref(), Some("Hi."));
+        assert_eq!(responses.usage().total_token_count, Some(5));
+        assert_eq!(client.history().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn local_function_is_called() {
+        let mut client = client().await;
+        client
+            .with_function("add", "Adds a and b.", Schema::default(), |args: Value| {
+                Ok(json!(
+                    args["a"].as_i64().unwrap_or(0) + args["b"].as_i64().unwrap_or(0)
+                ))
+            })
+            .expect("Expected function.");
+
+        let call = ContentResponse {
+            candidates: vec![Candidate {
+                content: Content::model(vec![Part::FunctionCall(FunctionCall {
+                    id: Some("call-1".to_string()),
+                    name: "add".to_string(),
+                    args: json!({"a": 1, "b": 2}).as_object().cloned(),
+                })]),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert!(
+            client
+                .process_tools(&[call])
+                .await
+                .expect("Expected tool call.")
+        );
+        assert!(matches!(
+            &client.history().last().expect("Expected function response.").parts[..],
+            [Part::FunctionResponse(response)]
+                if response.id.as_deref() == Some("call-1") && response.response["result"] == json!(3)
+        ));
+    }
+
+    #[test]
+    fn annotated_text_attaches_sources() {
+        let response: ContentResponse = serde_json::from_value(json!({
+            "candidates": [{
+                "content": {"role": "model", "parts": [{"text": "Rust is fast. It is safe."}]},
+                "groundingMetadata": {
+                    "groundingChunks": [
+                        {"web": {"uri": "https://a.example", "title": "a"}},
+                        {"web": {"uri": "https://b.example", "title": "b"}}
+                    ],
+                    "groundingSupports": [
+                        {"segment": {"endIndex": 13}, "groundingChunkIndices": [0]},
+                        {"segment": {"startIndex": 14, "endIndex": 25}, "groundingChunkIndices": [0, 1]}
+                    ],
+                    "retrievalMetadata": {}
+                }
+            }]
+        }))
+        .expect("Expected response.");
+        let responses = Responses {
+            responses: vec![response],
+            parse_errors: 0,
+            headers: Default::default(),
+            lenient_json: false,
+            request_starts: Vec::new(),
+        };
+
+        let segments = responses.annotated_text();
+        let rendered: Vec<_> = segments
+            .iter()
+            .map(|(text, sources)| {
+                (
+                    text.as_str(),
+                    sources.iter().map(|s| s.title.as_str()).collect::<Vec<_>>(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            rendered,
+            vec![
+                ("Rust is fast.", vec!["a"]),
+                (" ", vec![]),
+                ("It is safe.", vec!["a", "b"]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn dry_run_text_appends_user_message() {
+        let client = client().await;
+
+        let mut expected = client.request().clone();
+        expected
+            .contents
+            .push(Content::user(vec![Part::text("Hello")]));
+
+        assert_eq!(client.dry_run_text("Hello"), expected);
+        assert!(client.history().is_empty());
+    }
+
+    #[test]
+    fn decoded_responses() {
+        let responses: Responses = [ContentResponse {
+            candidates: vec![Candidate {
+                content: Content {
+                    parts: vec![
+                        Part::Text("Here you go".to_string()),
+                        Part::InlineData(Blob {
+                            mime_type: "image/png".to_string(),
+                            data: "iVBORw==".to_string(),
+                        }),
+                    ],
+                    role: Role::Model,
+                },
+                ..Default::default()
+            }],
+            ..Default::default()
+        }]
+        .into_iter()
+        .collect();
+
+        assert_eq!(
+            responses.images_decoded().expect("Expected images."),
+            vec![("image/png".to_string(), vec![0x89, b'P', b'N', b'G'])]
+        );
+    }
+
+    #[test]
+    fn prompt_and_candidate_safety_ratings_are_separate() {
+        let rating = |category, probability| SafetyRating {
+            category,
+            probability,
+            blocked: false,
+        };
+
+        let mut first = chunk("Hello");
+        first.prompt_feedback = Some(PromptFeedBack {
+            block_reason: None,
+            safety_ratings: vec![rating(
+                HarmCategory::HarmCategoryHarassment,
+                HarmProbability::Low,
+            )],
+        });
+        first.candidates[0].safety_ratings = vec![rating(
+            HarmCategory::HarmCategoryHateSpeech,
+            HarmProbability::Negligible,
+        )];
+        let mut last = chunk(", world.");
+        last.candidates[0].safety_ratings = vec![rating(
+            HarmCategory::HarmCategoryHateSpeech,
+            HarmProbability::Medium,
+        )];
+
+        let responses: Responses = [first, last].into_iter().collect();
+
+        assert_eq!(
+            responses.prompt_safety_ratings()[0].category,
+            HarmCategory::HarmCategoryHarassment
+        );
+        assert_eq!(
+            responses.candidate_safety_ratings()[0].probability,
+            HarmProbability::Medium
+        );
+    }
+
+    #[test]
+    fn search_entry_point_is_exposed() {
+        let response = serde_json::from_value::<ContentResponse>(json!({
+            "candidates": [{
+                "content": {"parts": [{"text": "It is sunny."}], "role": "model"},
+                "groundingMetadata": {
+                    "searchEntryPoint": {"renderedContent": "<div>chip</div>"},
+                    "groundingChunks": [{"web": {"uri": "https://example.com", "title": "example.com"}}],
+                    "webSearchQueries": ["weather today"]
+                }
+            }]
+        }))
+        .expect("Expected response.");
+        let responses: Responses = [response].into_iter().collect();
+
+        assert_eq!(
+            responses.search_entry_point(),
+            Some("<div>chip</div>".to_string())
+        );
+        assert_eq!(
+            responses
+                .grounding_metadata()
+                .expect("Expected grounding metadata.")
+                .web_search_queries,
+            vec!["weather today".to_string()]
+        );
+    }
+
+    #[test]
+    fn responses_to_content() {
+        let responses = Responses {
+            responses: vec![chunk("Hello"), chunk(", "), chunk("world.")],
+            parse_errors: 0,
+            headers: Default::default(),
+            lenient_json: false,
+            request_starts: Vec::new(),
+        };
+
+        assert_eq!(
+            responses.to_content(),
+            Some(Content::model(vec![Part::text("Hello, world.")]))
+        );
+    }
+
+    #[tokio::test]
+    async fn candidate_count_is_validated() {
+        let mut client = client().await;
+
+        assert!(client.with_candidate_count(0).is_err());
+        assert!(client.with_candidate_count(9).is_err());
+        assert_eq!(
+            client
+                .with_candidate_count(2)
+                .expect("Expected candidate count.")
+                .request()
+                .generation_config
+                .as_ref()
+                .and_then(|config| config.candidate_count),
+            Some(2)
+        );
+
+        let mut client = Client::new(
+            &GoogleModel::new(GoogleModelVariant::Gemini20FlashExpImageGen, None),
+            "",
+        )
+        .await
+        .expect("Expected client.");
+
+        assert!(client.with_candidate_count(2).is_err());
+        assert!(client.with_candidate_count(1).is_ok());
+    }
+
+    #[test]
+    fn candidates_text_is_keyed_by_index() {
+        let candidate = |index, text: &str| Candidate {
+            content: Content::model(vec![Part::text(text)]),
+            index: Some(index),
+            ..Default::default()
+        };
+        let responses: Responses = [ContentResponse {
+            candidates: vec![candidate(0, "first"), candidate(i32::MAX, "last")],
+            ..Default::default()
+        }]
+        .into_iter()
+        .collect();
+
+        let texts = responses.candidates_text();
+        assert_eq!(texts.len(), 2);
+        assert_eq!(texts[&0], "first");
+        assert_eq!(texts[&i32::MAX], "last");
+    }
+
+    #[tokio::test]
+    async fn malformed_function_call_is_an_error() {
+        let mut client = client().await;
+        client
+            .request
+            .contents
+            .push(Content::user(vec![Part::text("Hi")]));
+
+        let mut malformed = chunk("");
+        malformed.candidates[0].content.parts.clear();
+        malformed.candidates[0].finish_reason = Some(FinishReason::MalformedFunctionCall);
+
+        assert!(matches!(
+            client.merge_response(&[chunk("Partial"), malformed]),
+            Err(Error::MalformedFunctionCall)
+        ));
+        assert_eq!(client.history().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn file_data_is_validated() {
+        let client = client().await;
+
+        let file = |mime_type: &str, file_uri: &str| FileData {
+            mime_type: mime_type.to_string(),
+            file_uri: file_uri.to_string(),
+        };
+
+        assert!(
+            client
+                .check_file_data(&file(
+                    "application/pdf",
+                    "https://generativelanguage.googleapis.com/v1beta/files/abc"
+                ))
+                .is_ok()
+        );
+        assert!(
+            client
+                .check_file_data(&file("video/mp4", "https://www.youtube.com/watch?v=abc"))
+                .is_ok()
+        );
+        assert!(
+            client
+                .check_file_data(&file(
+                    "application/zip",
+                    "https://generativelanguage.googleapis.com/v1beta/files/abc"
+                ))
+                .is_err()
+        );
+        assert!(
+            client
+                .check_file_data(&file("image/png", "https://example.com/image.png"))
+                .is_err()
+        );
+        assert!(
+            client
+                .check_file_data(&file(
+                    "image/png",
+                    "https://generativelanguage.googleapis.com/v1alpha/files/abc"
+                ))
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn api_version_is_applied() {
+        let mut client = client().await;
+        assert_eq!(
+            client.url(),
+            "https://generativelanguage.googleapis.com/v1beta/models/gemini-2.0-flash:streamGenerateContent"
+        );
+
+        client.with_api_version("v1alpha");
+        assert_eq!(
+            client.url(),
+            "https://generativelanguage.googleapis.com/v1alpha/models/gemini-2.0-flash:streamGenerateContent"
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_with_tools_are_not_streamed() {
+        let mut client = client().await;
+        assert!(client.url().ends_with(":streamGenerateContent"));
+
+        client
+            .with_function("now", "Returns the time.", Schema::default(), |_| {
+                Ok(json!("noon"))
+            })
+            .expect("Expected function.");
+        assert!(client.url().ends_with(":generateContent"));
+
+        client.with_streaming_policy(StreamingPolicy::Always);
+        assert!(client.url().ends_with(":streamGenerateContent"));
+    }
+
+    #[tokio::test]
+    async fn token_usage_is_totalled() {
+        let usage = |total| {
+            let mut response = chunk("Hi");
+            response.usage_metadata = Some(UsageMetadata {
+                total_token_count: Some(total),
+                ..Default::default()
+            });
+            response
+        };
+
+        let mut client = client().await;
+        client.with_mock_responses(vec![usage(10), usage(32)]);
+
+        client.send_text("Hello").await.expect("Expected response.");
+        client.send_text("Again").await.expect("Expected response.");
+        assert_eq!(client.token_usage_total().total_token_count, Some(42));
+        assert_eq!(client.token_usage_total().prompt_token_count, None);
+
+        client.reset_usage();
+        assert_eq!(client.token_usage_total().total_token_count, None);
+    }
+
+    #[tokio::test]
+    async fn dynamic_retrieval_threshold_is_validated() {
+        let mut client = client().await;
+
+        assert!(client.with_dynamic_retrieval(1.5).is_err());
+        assert!(client.with_dynamic_retrieval(0.3).is_ok());
+        assert!(client.with_dynamic_retrieval(0.7).is_ok());
+        assert_eq!(client.request.tools.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn per_request_config_is_restored() {
+        let mut client = client().await;
+        client.with_mock_responses(vec![chunk("Hi")]);
+        let before = client.request.generation_config.clone();
+
+        client
+            .send_text_with_config(
+                "Hello",
+                &GenerationConfig {
+                    temperature: Some(1.5),
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("Expected response.");
+
+        assert_eq!(client.request.generation_config, before);
+    }
+
+    #[tokio::test]
+    async fn internal_error_before_output_is_retried() {
+        let internal = || ContentResponse {
+            error: Some(json!({"code": 500, "message": "Internal error", "status": "INTERNAL"})),
+            ..Default::default()
+        };
+
+        let mut client = client().await;
+        client.with_mock_responses(vec![internal(), internal(), chunk("Hi")]);
+        let responses = client.send_text("Hello").await.expect("Expected response.");
+        assert_eq!(responses.text(), Some("Hi".to_string()));
+
+        client
+            .with_stream_retries(0)
+            .with_mock_responses(vec![internal(), chunk("Hi")]);
+        assert!(matches!(
+            client.send_text("Hello").await,
+            Err(Error::Request { code: 500, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn pcm_audio_carries_the_sample_rate() {
+        let mut client = client().await;
+        client.with_mock_responses(vec![chunk("Hi")]);
+
+        assert!(client.send_audio_pcm(None, &[0, 0], 0).await.is_err());
+        client
+            .send_audio_pcm(Some("Transcribe this."), &[0, 0, 1, 0], 16_000)
+            .await
+            .expect("Expected response.");
+
+        assert_eq!(
+            client.history()[0].parts[1],
+            Part::InlineData(Blob {
+                mime_type: "audio/L16;rate=16000".to_string(),
+                data: "AAABAA==".to_string(),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn edited_images_leave_out_the_input() {
+        let mut image_gen = Client::new(
+            &GoogleModel::new(GoogleModelVariant::Gemini20FlashExpImageGen, None),
+            "",
+        )
+        .await
+        .expect("Expected client.");
+
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR";
+        let input = std::env::temp_dir().join(format!(
+            "google-gemini-rs-{}-edited_images_leave_out_the_input.png",
+            std::process::id()
+        ));
+        std::fs::write(&input, png).expect("Expected image file.");
+        let input = input.as_path();
+        let echoed = BASE64_STANDARD.encode(png);
+        let image = |data: &str| {
+            Part::InlineData(Blob {
+                mime_type: "image/png".to_string(),
+                data: data.to_string(),
+            })
+        };
+
+        image_gen.with_mock_responses(vec![ContentResponse {
+            candidates: vec![Candidate {
+                content: Content::model(vec![image(&echoed), image("iVBORw==")]),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }]);
+
+        assert_eq!(
+            image_gen
+                .edit_image("Give the penguin a hat.", input)
+                .await
+                .expect("Expected images."),
+            vec![("image/png".to_string(), "iVBORw==".to_string())]
+        );
+
+        assert!(
+            client()
+                .await
+                .edit_image("Give the penguin a hat.", input)
+                .await
+                .is_err()
+        );
+
+        let _ = std::fs::remove_file(input);
+    }
+
+    #[tokio::test]
+    async fn cassette_records_then_replays() {
+        let path = std::env::temp_dir().join(format!(
+            "google-gemini-rs-{}-cassette_records_then_replays.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let mut recording = client().await;
+        recording
+            .with_mock_responses(vec![chunk("Hello"), chunk("Goodbye")])
+            .with_cassette(&path)
+            .expect("Expected cassette.");
+        recording.send_text("Hi").await.expect("Expected response.");
+        recording
+            .send_text("Bye")
+            .await
+            .expect("Expected response.");
+
+        let mut replaying = client().await;
+        replaying.with_cassette(&path).expect("Expected cassette.");
+        assert_eq!(
+            replaying
+                .send_text("Hi")
+                .await
+                .expect("Expected response.")
+                .text(),
+            Some("Hello".to_string())
+        );
+        assert_eq!(
+            replaying
+                .send_text("Bye")
+                .await
+                .expect("Expected response.")
+                .text(),
+            Some("Goodbye".to_string())
+        );
+        assert!(replaying.send_text("Again").await.is_err());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn retryable_errors() {
+        let request = |code| Error::Request {
+            code,
+            message: String::new(),
+            status: String::new(),
+            reason: None,
+        };
+
+        assert!(request(429).is_retryable());
+        assert!(request(503).is_retryable());
+        assert!(!request(400).is_retryable());
+        assert!(!request(403).is_retryable());
+        assert!(!Error::NotFound(String::new()).is_retryable());
+    }
+
+    #[test]
+    fn auth_errors() {
+        let error = |value: Value| Error::from(&value);
+
+        assert!(error(json!({"code": 403, "status": "PERMISSION_DENIED"})).is_auth_error());
+        assert!(
+            error(json!({
+                "code": 400,
+                "message": "API key not valid. Please pass a valid API key.",
+                "status": "INVALID_ARGUMENT",
+                "details": [{"reason": "API_KEY_INVALID", "domain": "googleapis.com"}]
+            }))
+            .is_auth_error()
+        );
+        assert!(!error(json!({"code": 400, "status": "INVALID_ARGUMENT"})).is_auth_error());
+        assert!(!error(json!({"code": 429, "status": "RESOURCE_EXHAUSTED"})).is_auth_error());
+        assert!(Error::InvalidKey(String::new()).is_auth_error());
+    }
+
+    #[tokio::test]
+    async fn streamed_function_call_is_called_once() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+
+        let mut client = client().await;
+        client
+            .with_function("now", "Returns the time.", Schema::default(), move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(json!("noon"))
+            })
+            .expect("Expected function.");
+        client
+            .request
+            .contents
+            .push(Content::user(vec![Part::text("What time is it?")]));
+
+        let body = r#"[{"candidates": [{"content": {"parts": [{"text": "Let me "}], "role": "model"}}]},
+{"candidates": [{"content": {"parts": [{"text": "check."}], "role": "model"}}]},
+{"candidates": [{"content": {"parts": [{"functionCall": {"id": "call-1", "name": "now", "args": {}}}], "role": "model"}, "finishReason": "STOP"}]}]"#;
+
+        let frames: Vec<ContentResponse> = stream::parse_frames(body)
+            .into_iter()
+            .map(|frame| frame.expect("Expected frame."))
+            .collect();
+        let responses = client
+            .merge_response(&frames)
+            .expect("Expected merged responses.");
+
+        assert!(
+            client
+                .process_tools(&responses)
+                .await
+                .expect("Expected tool call.")
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let history = client.history();
+        assert_eq!(history.len(), 3);
+        assert!(matches!(
+            &history[1].parts[..],
+            [Part::Text(text), Part::FunctionCall(_)] if text == "Let me check."
+        ));
+        assert!(matches!(
+            &history[2].parts[..],
+            [Part::FunctionResponse(response)] if response.id.as_deref() == Some("call-1")
+        ));
+    }
+
+    #[test]
+    fn merged_responses_concatenate() {
+        let first: Responses = [chunk("Hello")].into_iter().collect();
+        let second: Responses = [chunk(", "), chunk("world.")].into_iter().collect();
+
+        let merged = first.merge(second);
+
+        assert_eq!(merged.inner().len(), 3);
+        assert_eq!(merged.text().as_deref(), Some("Hello, world."));
+    }
+
+    #[test]
+    fn merged_responses_total_their_usage() {
+        let reported = |text: &str, prompt, total| {
+            let mut frame = chunk(text);
+            frame.usage_metadata = Some(UsageMetadata {
+                prompt_token_count: Some(prompt),
+                total_token_count: Some(total),
+                ..Default::default()
+            });
+            frame
+        };
+        let first: Responses = [reported("Hello", 3, 5), reported(",", 3, 6)]
+            .into_iter()
+            .collect();
+        let second: Responses = [chunk(" world"), reported(".", 10, 12)]
+            .into_iter()
+            .collect();
+
+        let usage = first.merge(second).usage();
+
+        assert_eq!(usage.prompt_token_count, Some(13));
+        assert_eq!(usage.total_token_count, Some(18));
+    }
+
+    #[test]
+    fn best_text_by_logprob_picks_the_most_likely_candidate() {
+        let candidate = |index, text: &str, avg_logprobs| Candidate {
+            content: Content::model(vec![Part::text(text)]),
+            index: Some(index),
+            avg_logprobs: Some(avg_logprobs),
+            ..Default::default()
+        };
+        let responses: Responses = [ContentResponse {
+            candidates: vec![candidate(0, "first", -0.9), candidate(1, "second", -0.1)],
+            ..Default::default()
+        }]
+        .into_iter()
+        .collect();
+
+        assert_eq!(responses.text().as_deref(), Some("first"));
+        assert_eq!(responses.text_of(1).as_deref(), Some("second"));
+        assert_eq!(responses.best_text_by_logprob().as_deref(), Some("second"));
+    }
+
+    #[tokio::test]
+    async fn mock_responses_drive_the_tool_loop() {
+        let mut client = client().await;
+        client
+            .with_function("now", "Returns the time.", Schema::default(), |_| {
+                Ok(json!("noon"))
+            })
+            .expect("Expected function.");
+
+        let call = ContentResponse {
+            candidates: vec![Candidate {
+                content: Content::model(vec![Part::FunctionCall(FunctionCall {
+                    id: None,
+                    name: "now".to_string(),
+                    args: None,
+                })]),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        client.with_mock_responses(vec![call, chunk("It is noon.")]);
+
+        let responses = client
+            .send_text("What time is it?")
+            .await
+            .expect("Expected responses.");
+
+        assert_eq!(responses.text().as_deref(), Some("It is noon."));
+        assert_eq!(client.history().len(), 4);
+        assert!(client.send_text("Again?").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn cached_tools_are_kept_and_locked() {
+        let mut client = client().await;
+        client
+            .with_function("now", "Returns the time.", Schema::default(), |_| {
+                Ok(json!("noon"))
+            })
+            .expect("Expected function.");
+
+        // As `with_cached_system_and_tools` leaves the client once the cache is created.
+        client.cached_tools = std::mem::take(&mut client.request.tools);
+        client.with_cached_content("cachedContents/tools");
+
+        assert!(matches!(
+            client.with_function("later", "Added too late.", Schema::default(), |_| {
+                Ok(json!(null))
+            }),
+            Err(Error::UnsupportedConfig(_))
+        ));
+        assert_eq!(client.tools().count(), 1);
+        assert!(client.request.tools.is_empty());
+        assert!(client.url().contains(super::URL_GENERATE));
+
+        let call = ContentResponse {
+            candidates: vec![Candidate {
+                content: Content::model(vec![Part::FunctionCall(FunctionCall {
+                    id: None,
+                    name: "now".to_string(),
+                    args: None,
+                })]),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        client.with_mock_responses(vec![call, chunk("It is noon.")]);
+
+        let responses = client
+            .send_text("What time is it?")
+            .await
+            .expect("Expected responses.");
+        assert_eq!(responses.text().as_deref(), Some("It is noon."));
+
+        // Instructions front-loaded into the history would never reach the cache.
+        let mut image_gen = Client::new(
+            &GoogleModel::new(GoogleModelVariant::Gemini20FlashExpImageGen, None),
+            "",
+        )
+        .await
+        .expect("Expected client.");
+        image_gen.with_instructions("Draw in pastels.");
+        assert!(matches!(
+            image_gen
+                .with_cached_system_and_tools(std::time::Duration::from_secs(60))
+                .await,
+            Err(Error::UnsupportedConfig(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn schema_from_value_reports_dropped_keywords() {
+        let mut client = client().await;
+
+        let (client, dropped) = client
+            .with_response_schema_from_value(json!({
+                "$schema": "https://json-schema.org/draft/2020-12/schema",
+                "type": "object",
+                "properties": {
+                    "name": {"type": "string", "$ref": "#/$defs/name"}
+                }
+            }))
+            .expect("Expected schema.");
+
+        assert_eq!(dropped, vec!["$schema", "properties.name.$ref"]);
+        assert!(
+            client
+                .request
+                .generation_config
+                .as_ref()
+                .is_some_and(|config| config.response_schema.is_some())
+        );
+    }
+
+    #[tokio::test]
+    async fn thinking_budget_is_validated() {
+        let mut client = client().await;
+        assert!(client.with_thinking(Some(false), Some(1024)).is_err());
+
+        let mut client = Client::new(&GoogleModel::new(GoogleModelVariant::Gemini25Pro, None), "")
+            .await
+            .expect("Expected client.");
+
+        assert!(client.with_thinking(Some(true), Some(0)).is_err());
+        assert!(client.with_thinking(Some(true), Some(64)).is_err());
+        assert!(client.with_thinking(Some(true), Some(-1)).is_ok());
+        assert!(client.with_thinking(Some(true), Some(32_768)).is_ok());
+        assert!(client.with_thinking(None, Some(1024)).is_ok());
+        assert_eq!(
+            serde_json::to_value(&client.request.generation_config).expect("Expected JSON.")["thinkingConfig"],
+            json!({"thinkingBudget": 1024})
+        );
+    }
+
+    #[test]
+    fn absent_usage_contributes_nothing() {
+        let mut with_prompt = chunk("Hello");
+        with_prompt.usage_metadata = Some(UsageMetadata {
+            prompt_token_count: Some(3),
+            total_token_count: Some(5),
+            ..Default::default()
+        });
+        let mut with_total = chunk(".");
+        with_total.usage_metadata = Some(UsageMetadata {
+            total_token_count: Some(7),
+            ..Default::default()
+        });
+
+        let responses: Responses = [with_prompt, chunk(", world"), with_total]
+            .into_iter()
+            .collect();
+        assert_eq!(responses.usage().prompt_token_count, Some(3));
+        assert_eq!(responses.usage().total_token_count, Some(7));
+        assert_eq!(responses.usage().candidates_token_count, Some(0));
+
+        let responses: Responses = [chunk("Hello")].into_iter().collect();
+        assert_eq!(responses.usage().total_token_count, Some(0));
+        assert_eq!(responses.usage().prompt_token_count, Some(0));
+        assert_eq!(responses.cached_token_count(), 0);
     }
 }
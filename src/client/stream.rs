@@ -0,0 +1,197 @@
+//! Parsing of the `streamGenerateContent` response body.  The body is a JSON array of
+//! `ContentResponse` frames, or with `alt=sse` a series of `data:` lines, and proxies may
+//! reformat either as newline-delimited JSON.  The `generateContent` body is a single frame.
+//! Rather than parsing a particular framing we split out every top level JSON object and
+//! parse each one independently, so a single malformed frame does not lose the rest of the
+//! response.
+
+use crate::google::response::ContentResponse;
+
+use super::Error;
+
+/// Parse each frame of the streamed body independently.
+pub(crate) fn parse_frames(body: &str) -> Vec<Result<ContentResponse, Error>> {
+    split_frames(body).into_iter().map(parse_frame).collect()
+}
+
+fn parse_frame(frame: &str) -> Result<ContentResponse, Error> {
+    Ok(serde_json::from_str::<ContentResponse>(frame)?)
+}
+
+/// Incrementally parses the body as it arrives, returning each frame as soon as it is
+/// complete.
+#[derive(Default)]
+pub(crate) struct FrameReader {
+    buffer: Vec<u8>,
+    consumed: usize,
+}
+
+impl FrameReader {
+    /// Append a chunk of the body and parse the frames it completes.
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> Vec<Result<ContentResponse, Error>> {
+        self.buffer.extend_from_slice(chunk);
+
+        // Frames end on a `}` so `consumed` is always on a char boundary, but the chunk may
+        // end part way through a char.
+        let pending = &self.buffer[self.consumed..];
+        let pending = match std::str::from_utf8(pending) {
+            Ok(pending) => pending,
+            Err(error) => std::str::from_utf8(&pending[..error.valid_up_to()]).unwrap_or_default(),
+        };
+
+        let (frames, end) = split_complete_frames(pending);
+        let frames = frames.into_iter().map(parse_frame).collect();
+        self.consumed += end;
+
+        frames
+    }
+
+    /// Parse whatever remains of the body once it has been fully read.
+    pub(crate) fn finish(self) -> Vec<Result<ContentResponse, Error>> {
+        parse_frames(&String::from_utf8_lossy(&self.buffer[self.consumed..]))
+    }
+}
+
+/// Split the body into its top level JSON objects, ignoring anything between them such as
+/// array brackets, commas or `data:` prefixes.  A trailing unterminated object is returned
+/// as is so that it surfaces as a parse error.
+fn split_frames(body: &str) -> Vec<&str> {
+    let (mut frames, end) = split_complete_frames(body);
+
+    if let Some(start) = body[end..].find('{') {
+        frames.push(&body[end + start..]);
+    }
+
+    frames
+}
+
+/// Split out the complete top level JSON objects of the body, returning them along with
+/// the offset just past the last one.
+fn split_complete_frames(body: &str) -> (Vec<&str>, usize) {
+    let mut frames = Vec::new();
+    let mut end = 0;
+    let mut depth = 0usize;
+    let mut start = None;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, c) in body.char_indices() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+
+        match c {
+            '"' if depth > 0 => in_string = true,
+            '{' => {
+                if depth == 0 {
+                    start = Some(i);
+                }
+                depth += 1;
+            }
+            '}' => {
+                depth = depth.saturating_sub(1);
+                if depth == 0
+                    && let Some(start) = start.take()
+                {
+                    frames.push(&body[start..=i]);
+                    end = i + 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    (frames, end)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::google::common::Part;
+
+    use super::{FrameReader, parse_frames};
+
+    #[test]
+    fn malformed_frame_keeps_the_rest() {
+        let body = r#"[{"candidates": [{"content": {"parts": [{"text": "Hello {"}], "role": "model"}}]},
+{"candidates": [{"content": {"parts": [{"text": 42}], "role": "model"}}]},
+{"candidates": [{"content": {"parts": [{"text": "world."}], "role": "model"}}]},
+{"candidates": [{"content": {"#;
+
+        let frames = parse_frames(body);
+
+        assert_eq!(frames.len(), 4);
+        assert!(matches!(
+            &frames[0].as_ref().expect("Expected first frame.").candidates[0].content.parts[0],
+            Part::Text(text) if text == "Hello {"
+        ));
+        assert!(frames[1].is_err());
+        assert!(frames[2].is_ok());
+        assert!(frames[3].is_err());
+    }
+
+    fn texts(body: &str) -> Vec<String> {
+        parse_frames(body)
+            .into_iter()
+            .map(
+                |frame| match &frame.expect("Expected frame.").candidates[0].content.parts[0] {
+                    Part::Text(text) => text.clone(),
+                    _ => panic!("Expected text part."),
+                },
+            )
+            .collect()
+    }
+
+    #[test]
+    fn array_framing() {
+        let body = r#"[{"candidates": [{"content": {"parts": [{"text": "a"}], "role": "model"}}]}
+,
+{"candidates": [{"content": {"parts": [{"text": "b"}], "role": "model"}}]}
+]"#;
+
+        assert_eq!(texts(body), ["a", "b"]);
+    }
+
+    #[test]
+    fn newline_delimited_framing() {
+        let body = r#"{"candidates": [{"content": {"parts": [{"text": "a"}], "role": "model"}}]}
+{"candidates": [{"content": {"parts": [{"text": "b"}], "role": "model"}}]}
+"#;
+
+        assert_eq!(texts(body), ["a", "b"]);
+    }
+
+    #[test]
+    fn sse_framing() {
+        let body = r#"data: {"candidates": [{"content": {"parts": [{"text": "a \"quoted\" }"}], "role": "model"}}]}
+
+data: {"candidates": [{"content": {"parts": [{"text": "b"}], "role": "model"}}]}
+
+"#;
+
+        assert_eq!(texts(body), ["a \"quoted\" }", "b"]);
+    }
+
+    #[test]
+    fn reader_yields_frames_as_they_complete() {
+        let body = r#"[{"candidates": [{"content": {"parts": [{"text": "é"}], "role": "model"}}]},
+{"candidates": [{"content": {"parts": [{"text": "b"}], "role": "model"}}]}]"#
+            .as_bytes();
+
+        for split in 1..body.len() {
+            let mut reader = FrameReader::default();
+            let mut frames = reader.push(&body[..split]);
+            frames.extend(reader.push(&body[split..]));
+            frames.extend(reader.finish());
+
+            assert_eq!(frames.len(), 2);
+            assert!(frames.iter().all(Result::is_ok));
+        }
+    }
+}
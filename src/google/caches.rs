@@ -0,0 +1,181 @@
+//! Context caching types for Google AI Models. See: https://ai.google.dev/api/caching
+
+use std::{fmt::Display, str::FromStr, time::Duration};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+use super::{
+    common::Content,
+    request::{Tool, ToolConfig},
+};
+
+/// A `Duration` which serializes as the protobuf duration string the API expects, e.g.
+/// `"3600s"` or `"3.5s"`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DurationString(pub Duration);
+
+impl From<Duration> for DurationString {
+    fn from(value: Duration) -> Self {
+        Self(value)
+    }
+}
+
+impl From<DurationString> for Duration {
+    fn from(value: DurationString) -> Self {
+        value.0
+    }
+}
+
+impl Display for DurationString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let nanos = self.0.subsec_nanos();
+        if nanos == 0 {
+            write!(f, "{}s", self.0.as_secs())
+        } else {
+            let fraction = format!("{nanos:09}");
+            write!(
+                f,
+                "{}.{}s",
+                self.0.as_secs(),
+                fraction.trim_end_matches('0')
+            )
+        }
+    }
+}
+
+impl FromStr for DurationString {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid duration {s}");
+        let seconds = s.strip_suffix('s').ok_or_else(invalid)?;
+        let (whole, fraction) = seconds.split_once('.').unwrap_or((seconds, ""));
+        if fraction.len() > 9 || !fraction.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let secs = whole.parse::<u64>().map_err(|_| invalid())?;
+        let nanos = if fraction.is_empty() {
+            0
+        } else {
+            format!("{fraction:0<9}")
+                .parse::<u32>()
+                .map_err(|_| invalid())?
+        };
+
+        Ok(Self(Duration::new(secs, nanos)))
+    }
+}
+
+impl Serialize for DurationString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for DurationString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedContent {
+    /// The resource name in the form `cachedContents/{id}`, assigned by the API.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    /// The model name in the form `models/{model}`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_instruction: Option<Content>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub contents: Vec<Content>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<Tool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_config: Option<ToolConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<DurationString>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub create_time: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub update_time: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expire_time: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage_metadata: Option<CacheUsageMetadata>,
+    #[serde(default, skip_serializing)]
+    pub error: Option<Value>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheUsageMetadata {
+    #[serde(default)]
+    pub total_token_count: Option<i32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ListCachedContentsResponse {
+    #[serde(default)]
+    pub cached_contents: Vec<CachedContent>,
+    #[serde(default)]
+    pub next_page_token: Option<String>,
+    #[serde(default)]
+    pub error: Option<Value>,
+}
+
+/// A summary of a cached content, as listed by `Client::list_caches`.
+#[derive(Clone, Debug, Default)]
+pub struct CacheInfo {
+    pub name: String,
+    pub model: Option<String>,
+    pub create_time: Option<String>,
+    pub expire_time: Option<String>,
+    pub total_token_count: Option<i32>,
+}
+
+impl From<CachedContent> for CacheInfo {
+    fn from(value: CachedContent) -> Self {
+        Self {
+            name: value.name.unwrap_or_default(),
+            model: value.model,
+            create_time: value.create_time,
+            expire_time: value.expire_time,
+            total_token_count: value.usage_metadata.and_then(|u| u.total_token_count),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::DurationString;
+
+    #[test]
+    fn duration_string_round_trip() {
+        for (duration, wire) in [
+            (Duration::from_secs(3600), "3600s"),
+            (Duration::from_millis(3500), "3.5s"),
+            (Duration::new(0, 1), "0.000000001s"),
+        ] {
+            let json = serde_json::to_string(&DurationString(duration)).expect("Expected JSON.");
+            assert_eq!(json, format!("\"{wire}\""));
+            assert_eq!(
+                serde_json::from_str::<DurationString>(&json).expect("Expected duration."),
+                DurationString(duration)
+            );
+        }
+
+        assert!("3600".parse::<DurationString>().is_err());
+        assert!("1.2345678901s".parse::<DurationString>().is_err());
+    }
+}
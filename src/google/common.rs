@@ -1,21 +1,71 @@
 //! Common types and wrappers for Google AI Models. See: https://ai.google.dev/api/generate-content
 
+use std::{fmt::Display, str::FromStr};
+
+use base64::prelude::*;
 use enum_iterator::Sequence;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use serde_json::Value;
 
-#[derive(Debug, Clone, Serialize, Deserialize, Sequence)]
+/// Writes the wire string of a unit enum variant, e.g. `HARM_CATEGORY_HATE_SPEECH`.
+pub(crate) fn fmt_wire<T: Serialize>(
+    value: &T,
+    f: &mut std::fmt::Formatter<'_>,
+) -> std::fmt::Result {
+    match serde_json::to_value(value) {
+        Ok(Value::String(wire)) => write!(f, "{wire}"),
+        _ => Err(std::fmt::Error),
+    }
+}
+
+/// Parses a unit enum variant from its wire string.  Strings which only deserialize to the
+/// catch all variant, for which `is_unknown` returns true, are rejected.
+pub(crate) fn from_wire<T: DeserializeOwned>(
+    wire: &str,
+    is_unknown: impl Fn(&T) -> bool,
+) -> Result<T, serde_json::Error> {
+    let value = serde_json::from_value(Value::String(wire.to_string()))?;
+
+    if is_unknown(&value) {
+        return Err(serde::de::Error::custom(format!(
+            "unknown variant `{wire}`"
+        )));
+    }
+
+    Ok(value)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Sequence, Eq, PartialEq)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[non_exhaustive]
 pub enum HarmCategory {
     HarmCategoryHarassment,
     HarmCategoryHateSpeech,
     HarmCategorySexuallyExplicit,
     HarmCategoryDangerousContent,
     HarmCategoryCivicIntegrity,
+    /// A category returned by the API which is not yet modeled.
+    #[serde(other)]
+    Unknown,
+}
+
+impl Display for HarmCategory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt_wire(self, f)
+    }
+}
+
+impl FromStr for HarmCategory {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        from_wire(s, |value| matches!(value, HarmCategory::Unknown))
+    }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq, Eq)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[non_exhaustive]
 pub enum HarmProbability {
     HarmProbabilityUnspecified,
     Negligible,
@@ -23,33 +73,84 @@ pub enum HarmProbability {
     Low,
     Medium,
     High,
+    /// A probability returned by the API which is not yet modeled.
+    #[serde(other)]
+    Unknown,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Sequence)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[non_exhaustive]
 pub enum Modality {
     ModalityUnspecified,
     Text,
     Image,
     Audio,
     Video,
+    /// A modality returned by the API which is not yet modeled.
+    #[serde(other)]
+    Unknown,
+}
+
+impl Modality {
+    /// The input modality of the given mime type, or `Unknown` if it is not one the API
+    /// accepts.  Documents such as PDFs are read as text.
+    pub fn from_mime_type(mime_type: &str) -> Modality {
+        match mime_type.split_once('/') {
+            Some(("image", _)) => Modality::Image,
+            Some(("audio", _)) => Modality::Audio,
+            Some(("video", _)) => Modality::Video,
+            Some(("text", _)) | Some(("application", "pdf")) => Modality::Text,
+            _ => Modality::Unknown,
+        }
+    }
+}
+
+impl Display for Modality {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt_wire(self, f)
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl FromStr for Modality {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        from_wire(s, |value| matches!(value, Modality::Unknown))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct Blob {
     pub mime_type: String,
     pub data: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl Blob {
+    /// Returns the size of the decoded data in bytes, computed from the Base64 length without
+    /// decoding.  Padded and unpadded encodings are both accepted.
+    pub fn decoded_len(&self) -> usize {
+        let data = self.data.trim_end_matches('=');
+        data.len() / 4 * 3 + (data.len() % 4).saturating_sub(1)
+    }
+
+    /// Decodes the Base64 data, accepting either the URL safe or the standard alphabet.
+    pub fn decode(&self) -> Result<Vec<u8>, base64::DecodeError> {
+        BASE64_URL_SAFE
+            .decode(&self.data)
+            .or_else(|_| BASE64_STANDARD.decode(&self.data))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct FileData {
     pub mime_type: String,
     pub file_uri: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct FunctionCall {
     #[serde(default)]
@@ -59,7 +160,7 @@ pub struct FunctionCall {
     pub args: Option<serde_json::Map<String, Value>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct FunctionResponse {
     pub id: Option<String>,
@@ -67,22 +168,69 @@ pub struct FunctionResponse {
     pub response: serde_json::Map<String, Value>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+impl FunctionResponse {
+    /// Creates the response to a call of the named function.  Results which are not JSON
+    /// objects are sent as `{"result": value}`.
+    pub fn new(name: impl Into<String>, result: Value) -> Self {
+        let response = match result {
+            Value::Object(map) => map,
+            result => serde_json::Map::from_iter([("result".to_string(), result)]),
+        };
+
+        Self {
+            id: None,
+            name: name.into(),
+            response,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum Language {
     Python,
     LanguageUnspecified,
+    /// A language returned by the API which is not yet modeled.  The wire string is kept so
+    /// that the code round trips when the history is sent back.
+    Other(String),
+}
+
+impl Language {
+    fn wire(&self) -> &str {
+        match self {
+            Language::Python => "PYTHON",
+            Language::LanguageUnspecified => "LANGUAGE_UNSPECIFIED",
+            Language::Other(language) => language,
+        }
+    }
+}
+
+impl Serialize for Language {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.wire())
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl<'de> Deserialize<'de> for Language {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let wire = String::deserialize(deserializer)?;
+        Ok(match wire.as_str() {
+            "PYTHON" => Language::Python,
+            "LANGUAGE_UNSPECIFIED" => Language::LanguageUnspecified,
+            _ => Language::Other(wire),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct ExecutableCode {
     pub language: Language,
     pub code: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum Outcome {
     OutcomeUnspecified,
     OutcomeOk,
@@ -90,14 +238,14 @@ pub enum Outcome {
     OutcomeDeadlineExceeded,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct CodeExecutionResult {
     pub outcome: Outcome,
     pub output: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub enum Part {
     Thought(bool),
@@ -110,7 +258,9 @@ pub enum Part {
     CodeExecutionResult(CodeExecutionResult),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+/// The author of a `Content`.  The API has no function or tool role; function responses
+/// are sent as `User` content.
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum Role {
     #[default]
@@ -118,10 +268,337 @@ pub enum Role {
     Model,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct Content {
     #[serde(default)]
     pub parts: Vec<Part>,
     pub role: Role,
 }
+
+impl Part {
+    pub fn text(text: impl Into<String>) -> Self {
+        Part::Text(text.into())
+    }
+
+    pub fn inline_data(mime_type: impl Into<String>, data: impl Into<String>) -> Self {
+        Part::InlineData(Blob {
+            mime_type: mime_type.into(),
+            data: data.into(),
+        })
+    }
+
+    pub fn file_data(mime_type: impl Into<String>, file_uri: impl Into<String>) -> Self {
+        Part::FileData(FileData {
+            mime_type: mime_type.into(),
+            file_uri: file_uri.into(),
+        })
+    }
+
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Part::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn as_inline_data(&self) -> Option<&Blob> {
+        match self {
+            Part::InlineData(blob) => Some(blob),
+            _ => None,
+        }
+    }
+
+    /// Returns the inline data if it is an image.
+    pub fn as_image(&self) -> Option<&Blob> {
+        self.as_inline_data()
+            .filter(|blob| Modality::from_mime_type(&blob.mime_type) == Modality::Image)
+    }
+
+    pub fn as_file_data(&self) -> Option<&FileData> {
+        match self {
+            Part::FileData(file_data) => Some(file_data),
+            _ => None,
+        }
+    }
+
+    pub fn as_function_call(&self) -> Option<&FunctionCall> {
+        match self {
+            Part::FunctionCall(function_call) => Some(function_call),
+            _ => None,
+        }
+    }
+
+    pub fn as_function_response(&self) -> Option<&FunctionResponse> {
+        match self {
+            Part::FunctionResponse(function_response) => Some(function_response),
+            _ => None,
+        }
+    }
+
+    pub fn as_executable_code(&self) -> Option<&ExecutableCode> {
+        match self {
+            Part::ExecutableCode(executable_code) => Some(executable_code),
+            _ => None,
+        }
+    }
+
+    pub fn as_code_execution_result(&self) -> Option<&CodeExecutionResult> {
+        match self {
+            Part::CodeExecutionResult(result) => Some(result),
+            _ => None,
+        }
+    }
+}
+
+impl Content {
+    /// Creates user authored content from the given parts.
+    pub fn user(parts: Vec<Part>) -> Self {
+        Self {
+            parts,
+            role: Role::User,
+        }
+    }
+
+    /// Creates model authored content from the given parts.
+    pub fn model(parts: Vec<Part>) -> Self {
+        Self {
+            parts,
+            role: Role::Model,
+        }
+    }
+
+    /// Merge consecutive text parts into a single text part.  Streamed responses deliver a
+    /// single answer as many small text fragments which would otherwise bloat the history.
+    /// Non-text parts and their ordering are preserved.
+    pub fn coalesce_text(&mut self) {
+        let mut parts: Vec<Part> = Vec::with_capacity(self.parts.len());
+
+        for part in self.parts.drain(..) {
+            match (parts.last_mut(), part) {
+                (Some(Part::Text(previous)), Part::Text(text)) => previous.push_str(&text),
+                (_, part) => parts.push(part),
+            }
+        }
+
+        self.parts = parts;
+    }
+}
+
+/// Rough number of characters per token used to estimate token counts locally.
+pub const CHARS_PER_TOKEN: usize = 4;
+
+/// Split text which is too long for a single request into user contents of at most roughly
+/// `max_tokens` tokens each, estimated with `CHARS_PER_TOKEN`.  Text is only split on
+/// whitespace, so a single word longer than the budget becomes its own oversized chunk.
+pub fn split_text_into_contents(text: &str, max_tokens: usize) -> Vec<Content> {
+    let max_chars = max_tokens.max(1) * CHARS_PER_TOKEN;
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    let mut chunk_chars = 0;
+
+    for word in text.split_inclusive(char::is_whitespace) {
+        let word_chars = word.chars().count();
+        if !chunk.is_empty() && chunk_chars + word_chars > max_chars {
+            chunks.push(std::mem::take(&mut chunk));
+            chunk_chars = 0;
+        }
+        chunk.push_str(word);
+        chunk_chars += word_chars;
+    }
+
+    if !chunk.trim().is_empty() {
+        chunks.push(chunk);
+    }
+
+    chunks
+        .into_iter()
+        .map(|chunk| Content::user(vec![Part::text(chunk)]))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use base64::prelude::*;
+    use serde_json::json;
+
+    use super::{
+        Blob, CodeExecutionResult, Content, ExecutableCode, FunctionResponse, HarmCategory,
+        Language, Modality, Outcome, Part, Role, split_text_into_contents,
+    };
+
+    #[test]
+    fn blob_decoded_len_matches_decode() {
+        for bytes in [
+            &b""[..],
+            b"a",
+            b"ab",
+            b"abc",
+            b"abcd",
+            b"\xff\xfe\xfd\xfc\xfb",
+        ] {
+            for data in [
+                BASE64_STANDARD.encode(bytes),
+                BASE64_URL_SAFE_NO_PAD.encode(bytes),
+            ] {
+                let blob = Blob {
+                    mime_type: "application/octet-stream".to_string(),
+                    data,
+                };
+                assert_eq!(blob.decoded_len(), bytes.len());
+            }
+        }
+
+        let blob = Blob {
+            mime_type: "image/png".to_string(),
+            data: "_-8=".to_string(),
+        };
+        assert_eq!(blob.decode().expect("Expected bytes."), vec![0xff, 0xef]);
+    }
+
+    #[test]
+    fn unknown_languages_round_trip() {
+        let code = serde_json::from_value::<ExecutableCode>(json!({
+            "language": "JAVASCRIPT",
+            "code": "console.log(1)"
+        }))
+        .expect("Expected executable code.");
+        assert_eq!(code.language, Language::Other("JAVASCRIPT".to_string()));
+        assert_eq!(
+            serde_json::to_value(&code).expect("Expected JSON.")["language"],
+            "JAVASCRIPT"
+        );
+
+        assert_eq!(
+            serde_json::from_value::<Language>(json!("PYTHON")).expect("Expected language."),
+            Language::Python
+        );
+    }
+
+    #[test]
+    fn code_execution_result_outcome() {
+        let part = serde_json::from_value::<Part>(json!({
+            "codeExecutionResult": {
+                "outcome": "OUTCOME_OK",
+                "output": "2\n"
+            }
+        }))
+        .expect("Expected code execution result.");
+
+        assert_eq!(
+            part,
+            Part::CodeExecutionResult(CodeExecutionResult {
+                outcome: Outcome::OutcomeOk,
+                output: "2\n".to_string(),
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(Outcome::OutcomeDeadlineExceeded).expect("Expected JSON."),
+            "OUTCOME_DEADLINE_EXCEEDED"
+        );
+    }
+
+    #[test]
+    fn coalesce_text_preserves_other_parts() {
+        let mut content = Content {
+            parts: vec![
+                Part::Text("a".to_string()),
+                Part::Text("b".to_string()),
+                Part::InlineData(Blob {
+                    mime_type: "image/png".to_string(),
+                    data: String::new(),
+                }),
+                Part::Text("c".to_string()),
+                Part::Text("d".to_string()),
+            ],
+            role: Role::Model,
+        };
+
+        content.coalesce_text();
+
+        assert_eq!(content.parts.len(), 3);
+        assert!(matches!(&content.parts[0], Part::Text(text) if text == "ab"));
+        assert!(matches!(&content.parts[1], Part::InlineData(_)));
+        assert!(matches!(&content.parts[2], Part::Text(text) if text == "cd"));
+    }
+
+    #[test]
+    fn function_response_round_trip() {
+        let fixture = json!({
+            "parts": [{
+                "functionResponse": {
+                    "id": "call-1",
+                    "name": "say_hello_world",
+                    "response": {"text": "Hello World"}
+                }
+            }],
+            "role": "user"
+        });
+
+        let content = serde_json::from_value::<Content>(fixture.clone())
+            .expect("Expected function response content.");
+
+        assert!(matches!(content.role, Role::User));
+        assert!(matches!(
+            &content.parts[0],
+            Part::FunctionResponse(FunctionResponse { id: Some(id), .. }) if id == "call-1"
+        ));
+        assert_eq!(
+            serde_json::to_value(&content).expect("Expected serialized content."),
+            fixture
+        );
+    }
+
+    #[test]
+    fn wire_strings_round_trip() {
+        assert_eq!(
+            HarmCategory::HarmCategoryHateSpeech.to_string(),
+            "HARM_CATEGORY_HATE_SPEECH"
+        );
+        assert_eq!(
+            "HARM_CATEGORY_HATE_SPEECH".parse::<HarmCategory>().ok(),
+            Some(HarmCategory::HarmCategoryHateSpeech)
+        );
+        assert_eq!(Modality::Image.to_string(), "IMAGE");
+        assert!("PICTURE".parse::<Modality>().is_err());
+        assert!("UNKNOWN".parse::<HarmCategory>().is_err());
+    }
+
+    #[test]
+    fn modality_from_mime_type() {
+        assert_eq!(Modality::from_mime_type("image/png"), Modality::Image);
+        assert_eq!(Modality::from_mime_type("audio/wav"), Modality::Audio);
+        assert_eq!(Modality::from_mime_type("video/mp4"), Modality::Video);
+        assert_eq!(Modality::from_mime_type("text/plain"), Modality::Text);
+        assert_eq!(Modality::from_mime_type("application/pdf"), Modality::Text);
+        assert_eq!(
+            Modality::from_mime_type("application/zip"),
+            Modality::Unknown
+        );
+    }
+
+    #[test]
+    fn split_text_respects_budget() {
+        let text = "one two three four five six seven eight nine ten";
+
+        let contents = split_text_into_contents(text, 3);
+
+        assert!(contents.len() > 1);
+        for content in &contents {
+            assert!(matches!(&content.parts[..], [Part::Text(chunk)] if chunk.len() <= 12));
+        }
+        let joined = contents
+            .iter()
+            .flat_map(|content| &content.parts)
+            .filter_map(|part| match part {
+                Part::Text(chunk) => Some(chunk.as_str()),
+                _ => None,
+            })
+            .collect::<String>();
+        assert_eq!(joined, text);
+
+        // Characters, not bytes, are counted, so multi-byte text fills a chunk as well.
+        let contents = split_text_into_contents("日本語 日本語 日本語", 2);
+        assert_eq!(contents.len(), 2);
+    }
+}
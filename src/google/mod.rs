@@ -1,12 +1,17 @@
 //! Wrapper types for supported Google AI Models
 
-use std::fmt::Display;
+use std::{fmt::Display, ops::RangeInclusive};
+
+use enum_iterator::all;
 
 use thiserror::Error;
 
-use crate::google::common::Modality;
+use crate::google::common::{HarmCategory, Modality};
 
+pub mod caches;
 pub mod common;
+pub mod files;
+pub mod pricing;
 pub mod request;
 pub mod response;
 
@@ -25,6 +30,7 @@ const GEMINI_2_5_PRO: &str = "gemini-2.5-pro";
 /// Supported Google AI models.  Some models have different capabilities than others, so this
 /// enum may be used to branch the different capabilities.
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+#[non_exhaustive]
 pub enum GoogleModelVariant {
     Gemini20FlashExpImageGen,
     Gemini20Flash,
@@ -80,6 +86,63 @@ impl GoogleModelVariant {
         }
     }
 
+    fn harm_categories(&self) -> Vec<HarmCategory> {
+        match self {
+            GoogleModelVariant::Gemini20FlashExpImageGen | GoogleModelVariant::Gemini20Flash => {
+                all::<HarmCategory>()
+                    .filter(|category| *category != HarmCategory::Unknown)
+                    .collect()
+            }
+            // The 2.5 models reject a safety setting for civic integrity.
+            GoogleModelVariant::Gemini25Flash
+            | GoogleModelVariant::Gemini25Pro
+            | GoogleModelVariant::Gemini25FlashLight => all::<HarmCategory>()
+                .filter(|category| {
+                    !matches!(
+                        category,
+                        HarmCategory::HarmCategoryCivicIntegrity | HarmCategory::Unknown
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    fn supports_safety_off(&self) -> bool {
+        !matches!(self, GoogleModelVariant::Gemini20FlashExpImageGen)
+    }
+
+    fn supports_multiple_candidates(&self) -> bool {
+        !matches!(self, GoogleModelVariant::Gemini20FlashExpImageGen)
+    }
+
+    fn thinking_budget_range(&self) -> Option<RangeInclusive<i32>> {
+        match self {
+            GoogleModelVariant::Gemini20FlashExpImageGen | GoogleModelVariant::Gemini20Flash => {
+                None
+            }
+            GoogleModelVariant::Gemini25Flash => Some(0..=24_576),
+            GoogleModelVariant::Gemini25Pro => Some(128..=32_768),
+            GoogleModelVariant::Gemini25FlashLight => Some(512..=24_576),
+        }
+    }
+
+    fn supports_thinking_off(&self) -> bool {
+        matches!(
+            self,
+            GoogleModelVariant::Gemini25Flash | GoogleModelVariant::Gemini25FlashLight
+        )
+    }
+
+    fn input_token_limit(&self) -> i32 {
+        match self {
+            GoogleModelVariant::Gemini20FlashExpImageGen => 32_768,
+            GoogleModelVariant::Gemini20Flash
+            | GoogleModelVariant::Gemini25Flash
+            | GoogleModelVariant::Gemini25Pro
+            | GoogleModelVariant::Gemini25FlashLight => 1_048_576,
+        }
+    }
+
     fn outputs(&self) -> Vec<Modality> {
         match self {
             GoogleModelVariant::Gemini20FlashExpImageGen => {
@@ -121,6 +184,49 @@ impl GoogleModel {
     }
 }
 
+impl GoogleModel {
+    /// Returns the harm categories the model accepts safety settings for.
+    pub fn harm_categories(&self) -> Vec<HarmCategory> {
+        self.variant.harm_categories()
+    }
+
+    /// Returns the modalities the model cannot produce as output.
+    pub fn unsupported_output_modalities(&self) -> Vec<Modality> {
+        all::<Modality>()
+            .filter(|modality| {
+                !matches!(modality, Modality::ModalityUnspecified | Modality::Unknown)
+                    && !self.output.contains(modality)
+            })
+            .collect()
+    }
+
+    /// Returns the maximum number of input tokens the model accepts.
+    pub fn input_token_limit(&self) -> i32 {
+        self.variant.input_token_limit()
+    }
+
+    /// Returns true if the model accepts the `Off` harm block threshold.
+    pub fn supports_safety_off(&self) -> bool {
+        self.variant.supports_safety_off()
+    }
+
+    /// Returns the range of thinking budgets the model accepts, or `None` if the model does
+    /// not think.  Thinking models also accept -1 for a dynamic budget.
+    pub fn thinking_budget_range(&self) -> Option<RangeInclusive<i32>> {
+        self.variant.thinking_budget_range()
+    }
+
+    /// Returns true if thinking may be turned off with a budget of 0.
+    pub fn supports_thinking_off(&self) -> bool {
+        self.variant.supports_thinking_off()
+    }
+
+    /// Returns true if the model accepts a `candidate_count` greater than one.
+    pub fn supports_multiple_candidates(&self) -> bool {
+        self.variant.supports_multiple_candidates()
+    }
+}
+
 impl TryFrom<&str> for GoogleModel {
     type Error = Error;
 
@@ -0,0 +1,70 @@
+//! Approximate pricing for Google AI Models. See: https://ai.google.dev/gemini-api/docs/pricing
+
+use super::{GoogleModel, GoogleModelVariant, response::UsageMetadata};
+
+const TOKENS_PER_MILLION: f64 = 1_000_000.0;
+
+/// Per-million token rates, in USD, used to estimate the cost of a request from its
+/// `UsageMetadata`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelPricing {
+    pub input: f64,
+    pub output: f64,
+    pub cached: f64,
+    pub thinking: f64,
+}
+
+impl ModelPricing {
+    /// Published paid tier rates for prompts under 200k tokens.  Prices change, so treat
+    /// these as a reasonable default and construct your own `ModelPricing` when accuracy
+    /// matters.
+    pub fn for_model(model: &GoogleModel) -> Self {
+        match model.variant {
+            GoogleModelVariant::Gemini20FlashExpImageGen | GoogleModelVariant::Gemini20Flash => {
+                Self {
+                    input: 0.10,
+                    output: 0.40,
+                    cached: 0.025,
+                    thinking: 0.40,
+                }
+            }
+            GoogleModelVariant::Gemini25Flash => Self {
+                input: 0.30,
+                output: 2.50,
+                cached: 0.075,
+                thinking: 2.50,
+            },
+            GoogleModelVariant::Gemini25Pro => Self {
+                input: 1.25,
+                output: 10.00,
+                cached: 0.31,
+                thinking: 10.00,
+            },
+            GoogleModelVariant::Gemini25FlashLight => Self {
+                input: 0.10,
+                output: 0.40,
+                cached: 0.025,
+                thinking: 0.40,
+            },
+        }
+    }
+
+    /// Estimate the cost in USD of the given usage.  Cached tokens are included in the
+    /// prompt token count by the API, so they are billed at the cached rate instead of the
+    /// input rate.
+    pub fn estimate(&self, usage: &UsageMetadata) -> f64 {
+        let cached = usage.cached_content_token_count.unwrap_or(0).max(0) as f64;
+        let prompt = usage.prompt_token_count.unwrap_or(0).max(0) as f64;
+        let tool_use = usage.tool_use_prompt_token_count.unwrap_or(0).max(0) as f64;
+        let output = usage.candidates_token_count.unwrap_or(0).max(0) as f64;
+        let thinking = usage.thoughts_token_count.unwrap_or(0).max(0) as f64;
+
+        let input = (prompt - cached).max(0.0) + tool_use;
+
+        (input * self.input
+            + cached * self.cached
+            + output * self.output
+            + thinking * self.thinking)
+            / TOKENS_PER_MILLION
+    }
+}
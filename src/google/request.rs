@@ -1,25 +1,30 @@
 //! Request types and wrappers for Google AI Models. See: https://ai.google.dev/api/generate-content
 
-use std::collections::HashMap;
-
+use indexmap::IndexMap;
+#[cfg(feature = "mcp")]
 use rust_mcp_sdk::{error::McpSdkError, schema::ToolInputSchema};
 use serde::{Deserialize, Serialize};
-use serde_json::{Value, json};
+use serde_json::Value;
 use thiserror::Error;
 
-use super::common::{Content, HarmCategory, Modality};
+use super::common::{Content, HarmCategory, Modality, Role};
 
 #[derive(Debug, Error)]
 pub enum Error {
+    #[cfg(feature = "mcp")]
     #[error(transparent)]
     McpSdk(#[from] McpSdkError),
     #[error("{0}")]
     NotFound(String),
     #[error(transparent)]
     Serde(#[from] serde_json::Error),
+    #[error("{0}")]
+    Unsupported(String),
+    #[error("Invalid history: {0}")]
+    InvalidHistory(String),
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq, Eq)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum Type {
     #[serde(alias = "typeunspecified")]
@@ -41,7 +46,7 @@ pub enum Type {
     Null,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Schema {
     pub r#type: Type,
@@ -55,21 +60,45 @@ pub struct Schema {
     pub nullable: Option<bool>,
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub r#enum: Vec<String>,
-    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "string_or_number"
+    )]
     pub max_items: Option<String>,
-    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "string_or_number"
+    )]
     pub min_items: Option<String>,
-    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
-    pub properties: HashMap<String, Schema>,
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub properties: IndexMap<String, Schema>,
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub required: Vec<String>,
-    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "string_or_number"
+    )]
     pub min_properties: Option<String>,
-    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "string_or_number"
+    )]
     pub max_properties: Option<String>,
-    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "string_or_number"
+    )]
     pub min_length: Option<String>,
-    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "string_or_number"
+    )]
     pub max_length: Option<String>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub pattern: Option<String>,
@@ -89,17 +118,189 @@ pub struct Schema {
     pub maximum: Option<f32>,
 }
 
+/// The keywords `Schema` models.  Any other keyword in a JSON Schema is dropped.
+const SCHEMA_KEYWORDS: [&str; 22] = [
+    "type",
+    "format",
+    "title",
+    "description",
+    "nullable",
+    "enum",
+    "maxItems",
+    "minItems",
+    "properties",
+    "required",
+    "minProperties",
+    "maxProperties",
+    "minLength",
+    "maxLength",
+    "pattern",
+    "example",
+    "anyOf",
+    "propertyOrdering",
+    "default",
+    "items",
+    "minimum",
+    "maximum",
+];
+
+/// Deserializes an int64 field, which JSON Schema writes as a number but the API as a
+/// string.
+fn string_or_number<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Option::<Value>::deserialize(deserializer)? {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value)),
+        Some(Value::Number(value)) => Ok(Some(value.to_string())),
+        Some(value) => Err(serde::de::Error::custom(format!(
+            "Expected a string or number, found {value}"
+        ))),
+    }
+}
+
+/// Collects the paths of the keywords of the JSON Schema which `Schema` does not model.
+fn unsupported_keywords(value: &Value, path: &str, dropped: &mut Vec<String>) {
+    let Some(map) = value.as_object() else {
+        return;
+    };
+
+    for (key, child) in map {
+        let child_path = if path.is_empty() {
+            key.clone()
+        } else {
+            format!("{path}.{key}")
+        };
+
+        match key.as_str() {
+            "properties" => {
+                for (name, property) in child.as_object().into_iter().flatten() {
+                    unsupported_keywords(property, &format!("{child_path}.{name}"), dropped);
+                }
+            }
+            "items" => unsupported_keywords(child, &child_path, dropped),
+            "anyOf" => {
+                for (index, schema) in child.as_array().into_iter().flatten().enumerate() {
+                    unsupported_keywords(schema, &format!("{child_path}.{index}"), dropped);
+                }
+            }
+            key if SCHEMA_KEYWORDS.contains(&key) => {}
+            _ => dropped.push(child_path),
+        }
+    }
+}
+
+impl Schema {
+    /// Converts a JSON Schema into a `Schema`, returning it along with the paths of the
+    /// keywords which were dropped because `Schema` cannot express them, e.g. `$ref`.
+    pub fn from_value(value: Value) -> Result<(Schema, Vec<String>), Error> {
+        let mut dropped = Vec::new();
+        unsupported_keywords(&value, "", &mut dropped);
+
+        let mut schema = serde_json::from_value::<Schema>(value)?;
+        schema.order_properties();
+
+        Ok((schema, dropped))
+    }
+
+    /// Orders the properties, recursively, by `property_ordering` followed by any remaining
+    /// properties in alphabetical order so that the serialized schema is deterministic.
+    pub fn order_properties(&mut self) {
+        let ordering = &self.property_ordering;
+
+        self.properties.sort_by(|a, _, b, _| {
+            let a_pos = ordering.iter().position(|name| name == a);
+            let b_pos = ordering.iter().position(|name| name == b);
+
+            match (a_pos, b_pos) {
+                (Some(a_pos), Some(b_pos)) => a_pos.cmp(&b_pos),
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (None, None) => a.cmp(b),
+            }
+        });
+
+        self.properties
+            .values_mut()
+            .chain(self.items.as_deref_mut())
+            .chain(self.any_of.iter_mut())
+            .for_each(Schema::order_properties);
+    }
+}
+
+/// Builds an object schema, e.g. for structured output, with an explicit property order.
+#[derive(Clone, Debug, Default)]
+pub struct SchemaBuilder {
+    schema: Schema,
+}
+
+impl SchemaBuilder {
+    pub fn object() -> Self {
+        Self {
+            schema: Schema {
+                r#type: Type::Object,
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn description(mut self, description: &str) -> Self {
+        self.schema.description = Some(description.to_string());
+        self
+    }
+
+    pub fn property(mut self, name: &str, schema: Schema) -> Self {
+        self.schema.properties.insert(name.to_string(), schema);
+        self
+    }
+
+    pub fn required(mut self, names: &[&str]) -> Self {
+        self.schema.required = names.iter().map(|name| name.to_string()).collect();
+        self
+    }
+
+    /// Sets the order in which the model emits the properties, sent as `propertyOrdering`.
+    pub fn order(mut self, names: &[&str]) -> Self {
+        self.schema.property_ordering = names.iter().map(|name| name.to_string()).collect();
+        self
+    }
+
+    /// Validates that every required and ordered name is a property and returns the schema
+    /// with its properties in order.
+    pub fn build(mut self) -> Result<Schema, Error> {
+        if let Some(name) = self
+            .schema
+            .required
+            .iter()
+            .chain(&self.schema.property_ordering)
+            .find(|name| !self.schema.properties.contains_key(*name))
+        {
+            return Err(Error::NotFound(format!("Schema property {name}")));
+        }
+
+        self.schema.order_properties();
+
+        Ok(self.schema)
+    }
+}
+
+#[cfg(feature = "mcp")]
 impl TryFrom<ToolInputSchema> for Schema {
     type Error = Error;
 
     fn try_from(value: ToolInputSchema) -> Result<Self, Error> {
         // Behold the power of serde: convert the MCP tool schema to the
         // Gemini tool schema.
-        Ok(serde_json::from_value::<Schema>(json!(value))?)
+        let mut schema = serde_json::from_value::<Schema>(serde_json::json!(value))?;
+
+        schema.order_properties();
+
+        Ok(schema)
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct FunctionDeclaration {
     pub name: String,
@@ -122,6 +323,7 @@ pub fn unmap_fn_name(name: &str) -> Result<String, Error> {
         .to_string())
 }
 
+#[cfg(feature = "mcp")]
 impl From<&rust_mcp_sdk::schema::Tool> for FunctionDeclaration {
     fn from(value: &rust_mcp_sdk::schema::Tool) -> Self {
         Self {
@@ -136,31 +338,32 @@ impl From<&rust_mcp_sdk::schema::Tool> for FunctionDeclaration {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum Mode {
     ModeUnspecified,
     ModeDynamic,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct DynamicRetrievalConfig {
     pub mode: Mode,
-    pub dynamic_threshold: i32,
+    /// The threshold in `[0.0, 1.0]` above which a prompt is grounded with search.
+    pub dynamic_threshold: f32,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct GoogleSearchRetrieval {
     pub dynamic_retrieval_config: DynamicRetrievalConfig,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct UrlContext {}
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Tool {
     #[serde(skip_serializing_if = "Vec::is_empty")]
@@ -175,6 +378,7 @@ pub struct Tool {
     pub url_context: Option<UrlContext>,
 }
 
+#[cfg(feature = "mcp")]
 impl From<Vec<rust_mcp_sdk::schema::Tool>> for Tool {
     fn from(value: Vec<rust_mcp_sdk::schema::Tool>) -> Self {
         Self {
@@ -187,7 +391,106 @@ impl From<Vec<rust_mcp_sdk::schema::Tool>> for Tool {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Builds the tools of a request from function declarations and the built-in tools,
+/// rejecting combinations which the API does not accept.
+#[derive(Clone, Debug, Default)]
+pub struct ToolBuilder {
+    pub(crate) functions: Vec<Vec<FunctionDeclaration>>,
+    google_search: bool,
+    google_search_retrieval: Option<GoogleSearchRetrieval>,
+    code_execution: bool,
+    url_context: bool,
+}
+
+impl ToolBuilder {
+    /// Adds a group of function declarations, which are sent as a single tool.
+    pub fn function_declarations(mut self, declarations: Vec<FunctionDeclaration>) -> Self {
+        self.functions.push(declarations);
+        self
+    }
+
+    pub fn google_search(mut self) -> Self {
+        self.google_search = true;
+        self
+    }
+
+    pub fn google_search_retrieval(mut self, retrieval: GoogleSearchRetrieval) -> Self {
+        self.google_search_retrieval = Some(retrieval);
+        self
+    }
+
+    pub fn code_execution(mut self) -> Self {
+        self.code_execution = true;
+        self
+    }
+
+    pub fn url_context(mut self) -> Self {
+        self.url_context = true;
+        self
+    }
+
+    /// Validates the combination of tools and returns them in request order: one tool per
+    /// group of function declarations followed by one tool per built-in tool.
+    pub fn build(self) -> Result<Vec<Tool>, Error> {
+        let built_in = self.google_search
+            || self.google_search_retrieval.is_some()
+            || self.code_execution
+            || self.url_context;
+
+        if built_in && !self.functions.is_empty() {
+            return Err(Error::Unsupported(
+                "Function declarations may not be combined with built-in tools".to_string(),
+            ));
+        }
+
+        if self.google_search && self.google_search_retrieval.is_some() {
+            return Err(Error::Unsupported(
+                "Google search may not be combined with Google search retrieval".to_string(),
+            ));
+        }
+
+        let mut tools: Vec<Tool> = self
+            .functions
+            .into_iter()
+            .map(|function_declarations| Tool {
+                function_declarations,
+                ..Default::default()
+            })
+            .collect();
+
+        if self.google_search {
+            tools.push(Tool {
+                google_search: Some(Value::Object(Default::default())),
+                ..Default::default()
+            });
+        }
+
+        if let Some(retrieval) = self.google_search_retrieval {
+            tools.push(Tool {
+                google_search_retrieval: Some(retrieval),
+                ..Default::default()
+            });
+        }
+
+        if self.code_execution {
+            tools.push(Tool {
+                code_execution: Some(Value::Object(Default::default())),
+                ..Default::default()
+            });
+        }
+
+        if self.url_context {
+            tools.push(Tool {
+                url_context: Some(UrlContext {}),
+                ..Default::default()
+            });
+        }
+
+        Ok(tools)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct FunctionCallingConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -196,14 +499,14 @@ pub struct FunctionCallingConfig {
     pub allowed_function_names: Vec<String>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct ToolConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub function_calling_config: Option<FunctionCallingConfig>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum HarmBlockThreshold {
     BlockNone,
@@ -215,26 +518,26 @@ pub enum HarmBlockThreshold {
     Off,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct SafetySettings {
     pub category: HarmCategory,
     pub threshold: HarmBlockThreshold,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct PrebuiltVoiceConfig {
     pub voice_name: String,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct VoiceConfig {
     pub prebuilt_voice_config: PrebuiltVoiceConfig,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct SpeechConfig {
     pub voice_config: VoiceConfig,
@@ -242,14 +545,17 @@ pub struct SpeechConfig {
     pub language_code: Option<String>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Fields left as `None` are omitted, so the model's default applies.
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct ThinkingConfig {
-    pub include_thoughts: bool,
-    pub thinking_budget: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_thoughts: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thinking_budget: Option<i32>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum MediaResolution {
     MediaResolutionUnspecified,
@@ -258,7 +564,8 @@ pub enum MediaResolution {
     MediaResolutionHigh,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+/// `PartialEq` compares the float fields, such as `temperature`, exactly.
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct GenerationConfig {
     #[serde(skip_serializing_if = "Vec::is_empty")]
@@ -267,6 +574,10 @@ pub struct GenerationConfig {
     pub response_mime_type: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub response_schema: Option<Schema>,
+    /// A full JSON Schema, which supports constructs such as `$ref` and `oneOf` that
+    /// `Schema` cannot express.  Mutually exclusive with `response_schema`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_json_schema: Option<Value>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub response_modalities: Vec<Modality>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -299,12 +610,54 @@ pub struct GenerationConfig {
     pub media_resolution: Option<MediaResolution>,
 }
 
+impl GenerationConfig {
+    /// Overlay the set fields of `other`, i.e. those which are `Some` or non-empty, onto this
+    /// config, leaving the remaining fields untouched.
+    pub fn merge(&mut self, other: &GenerationConfig) {
+        fn overlay<T: Clone>(field: &mut Option<T>, other: &Option<T>) {
+            if other.is_some() {
+                field.clone_from(other);
+            }
+        }
+
+        if !other.stop_sequences.is_empty() {
+            self.stop_sequences.clone_from(&other.stop_sequences);
+        }
+        if !other.response_modalities.is_empty() {
+            self.response_modalities
+                .clone_from(&other.response_modalities);
+        }
+        overlay(&mut self.response_mime_type, &other.response_mime_type);
+        overlay(&mut self.response_schema, &other.response_schema);
+        overlay(&mut self.response_json_schema, &other.response_json_schema);
+        overlay(&mut self.candidate_count, &other.candidate_count);
+        overlay(&mut self.max_output_tokens, &other.max_output_tokens);
+        overlay(&mut self.temperature, &other.temperature);
+        overlay(&mut self.top_p, &other.top_p);
+        overlay(&mut self.top_k, &other.top_k);
+        overlay(&mut self.seed, &other.seed);
+        overlay(&mut self.presence_penalty, &other.presence_penalty);
+        overlay(&mut self.frequency_penalty, &other.frequency_penalty);
+        overlay(&mut self.response_logprobs, &other.response_logprobs);
+        overlay(&mut self.logprobs, &other.logprobs);
+        overlay(
+            &mut self.enable_enhanced_civic_answers,
+            &other.enable_enhanced_civic_answers,
+        );
+        overlay(&mut self.speech_config, &other.speech_config);
+        overlay(&mut self.thinking_config, &other.thinking_config);
+        overlay(&mut self.media_resolution, &other.media_resolution);
+    }
+}
+
 /// Helper enum for updating portion of the GenerationConfig struct.
-#[derive(Clone, Debug)]
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum UpdateGenConfig {
     StopSequences(Vec<String>),
     ResponseMimeType(Option<String>),
     ResponseSchema(Option<Schema>),
+    ResponseJsonSchema(Option<Value>),
     ResponseModalities(Vec<Modality>),
     CandidateCount(Option<i32>),
     MaxOutputTokens(Option<i32>),
@@ -322,7 +675,7 @@ pub enum UpdateGenConfig {
     MediaResolution(Option<MediaResolution>),
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct GenerateContentRequest {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -341,3 +694,242 @@ pub struct GenerateContentRequest {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub cached_content: Option<String>,
 }
+
+impl GenerateContentRequest {
+    /// Checks that the history starts and ends with a user turn and that model turns are
+    /// separated by user turns, which the API otherwise rejects with a 400.  Consecutive user
+    /// turns are accepted.
+    pub fn validate_roles(&self) -> Result<(), Error> {
+        let Some(first) = self.contents.first() else {
+            return Err(Error::InvalidHistory("The history is empty".to_string()));
+        };
+
+        if first.role != Role::User {
+            return Err(Error::InvalidHistory(
+                "The first turn must be a user turn".to_string(),
+            ));
+        }
+
+        if self.contents.last().map(|content| &content.role) != Some(&Role::User) {
+            return Err(Error::InvalidHistory(
+                "The last turn must be a user turn".to_string(),
+            ));
+        }
+
+        if let Some(index) = self
+            .contents
+            .windows(2)
+            .position(|turns| turns[0].role == Role::Model && turns[1].role == Role::Model)
+        {
+            return Err(Error::InvalidHistory(format!(
+                "Turns {index} and {} are consecutive model turns",
+                index + 1
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// A `GenerateContentRequest` along with the name of the model it is for, as embedded in
+/// other requests.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ModelRequest {
+    /// The model name in the form `models/{model}`.
+    pub model: String,
+    #[serde(flatten)]
+    pub request: GenerateContentRequest,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CountTokensRequest {
+    pub generate_content_request: ModelRequest,
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use crate::google::common::Content;
+
+    use super::{
+        DynamicRetrievalConfig, GenerateContentRequest, GenerationConfig, Mode, Schema,
+        SchemaBuilder, Type,
+    };
+
+    #[test]
+    fn roles_are_validated() {
+        let request = |contents: Vec<Content>| GenerateContentRequest {
+            system_instruction: None,
+            contents,
+            tools: vec![],
+            tool_config: None,
+            safety_settings: vec![],
+            generation_config: None,
+            cached_content: None,
+        };
+
+        assert!(
+            request(vec![
+                Content::user(vec![]),
+                Content::model(vec![]),
+                Content::user(vec![])
+            ])
+            .validate_roles()
+            .is_ok()
+        );
+        assert!(request(vec![]).validate_roles().is_err());
+        assert!(
+            request(vec![Content::model(vec![]), Content::user(vec![])])
+                .validate_roles()
+                .is_err()
+        );
+        assert!(
+            request(vec![Content::user(vec![]), Content::model(vec![])])
+                .validate_roles()
+                .is_err()
+        );
+        assert!(
+            request(vec![
+                Content::user(vec![]),
+                Content::model(vec![]),
+                Content::model(vec![]),
+                Content::user(vec![]),
+            ])
+            .validate_roles()
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn dynamic_threshold_is_a_float() {
+        let config = DynamicRetrievalConfig {
+            mode: Mode::ModeDynamic,
+            dynamic_threshold: 0.3,
+        };
+
+        let value = serde_json::to_value(&config).expect("Expected JSON.");
+        assert_eq!(
+            value,
+            json!({"mode": "MODE_DYNAMIC", "dynamicThreshold": 0.3f32})
+        );
+        assert_eq!(
+            serde_json::from_value::<DynamicRetrievalConfig>(value).expect("Expected config."),
+            config
+        );
+    }
+
+    #[test]
+    fn merge_preserves_untouched_fields() {
+        let mut config = GenerationConfig {
+            temperature: Some(0.5),
+            top_k: Some(40),
+            stop_sequences: vec!["END".to_string()],
+            ..Default::default()
+        };
+
+        config.merge(&GenerationConfig {
+            temperature: Some(0.9),
+            max_output_tokens: Some(128),
+            ..Default::default()
+        });
+
+        assert_eq!(
+            config,
+            GenerationConfig {
+                temperature: Some(0.9),
+                top_k: Some(40),
+                max_output_tokens: Some(128),
+                stop_sequences: vec!["END".to_string()],
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn properties_follow_property_ordering() {
+        let mut schema = serde_json::from_value::<Schema>(json!({
+            "type": "object",
+            "properties": {
+                "zebra": {"type": "string"},
+                "apple": {"type": "string"},
+                "mango": {
+                    "type": "object",
+                    "properties": {"b": {"type": "string"}, "a": {"type": "string"}}
+                }
+            },
+            "propertyOrdering": ["mango", "zebra"]
+        }))
+        .expect("Expected schema.");
+
+        schema.order_properties();
+
+        assert_eq!(
+            schema.properties.keys().collect::<Vec<_>>(),
+            ["mango", "zebra", "apple"]
+        );
+        assert_eq!(
+            schema.properties["mango"]
+                .properties
+                .keys()
+                .collect::<Vec<_>>(),
+            ["a", "b"]
+        );
+    }
+
+    #[test]
+    fn schema_builder_serializes_property_ordering() {
+        let schema = SchemaBuilder::object()
+            .property(
+                "summary",
+                Schema {
+                    r#type: Type::String,
+                    ..Default::default()
+                },
+            )
+            .property(
+                "title",
+                Schema {
+                    r#type: Type::String,
+                    ..Default::default()
+                },
+            )
+            .order(&["title", "summary"])
+            .build()
+            .expect("Expected schema.");
+
+        assert_eq!(
+            schema.properties.keys().collect::<Vec<_>>(),
+            vec!["title", "summary"]
+        );
+
+        let value = serde_json::to_value(&schema).expect("Expected JSON.");
+        assert_eq!(value["propertyOrdering"], json!(["title", "summary"]));
+
+        let round_trip = serde_json::from_value::<Schema>(value).expect("Expected schema.");
+        assert_eq!(round_trip.property_ordering, vec!["title", "summary"]);
+
+        assert!(SchemaBuilder::object().order(&["missing"]).build().is_err());
+    }
+
+    #[test]
+    fn from_value_reports_dropped_keywords() {
+        let (schema, dropped) = Schema::from_value(json!({
+            "type": "object",
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "properties": {
+                "tags": {
+                    "type": "array",
+                    "maxItems": 3,
+                    "items": {"type": "string", "$ref": "#/$defs/tag"}
+                }
+            }
+        }))
+        .expect("Expected schema.");
+
+        assert_eq!(schema.properties["tags"].max_items.as_deref(), Some("3"));
+        assert_eq!(dropped, vec!["$schema", "properties.tags.items.$ref"]);
+    }
+}
@@ -1,12 +1,15 @@
 //! Response types and wrappers for Google AI Models. See: https://ai.google.dev/api/generate-content
 
+use std::{fmt::Display, str::FromStr};
+
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use super::common::{Content, HarmCategory, HarmProbability, Modality};
+use super::common::{Content, HarmCategory, HarmProbability, Modality, fmt_wire, from_wire};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[non_exhaustive]
 pub enum FinishReason {
     FinishReasonUnspecified,
     Stop,
@@ -20,10 +23,28 @@ pub enum FinishReason {
     Spii,
     MalformedFunctionCall,
     ImageSafety,
+    /// A finish reason returned by the API which is not yet modeled.
+    #[serde(other)]
+    Unknown,
+}
+
+impl Display for FinishReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt_wire(self, f)
+    }
+}
+
+impl FromStr for FinishReason {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        from_wire(s, |value| matches!(value, FinishReason::Unknown))
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict", serde(deny_unknown_fields))]
 pub struct SafetyRating {
     pub category: HarmCategory,
     pub probability: HarmProbability,
@@ -33,6 +54,7 @@ pub struct SafetyRating {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict", serde(deny_unknown_fields))]
 pub struct CitationSource {
     #[serde(default)]
     pub start_index: Option<u32>,
@@ -46,6 +68,7 @@ pub struct CitationSource {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict", serde(deny_unknown_fields))]
 pub struct CitationMetadata {
     #[serde(default)]
     pub citation_sources: Vec<CitationSource>,
@@ -53,6 +76,7 @@ pub struct CitationMetadata {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict", serde(deny_unknown_fields))]
 pub struct GroundingPassageId {
     pub passage_id: String,
     pub part_index: i32,
@@ -60,6 +84,7 @@ pub struct GroundingPassageId {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict", serde(deny_unknown_fields))]
 pub struct SemanticRetrieverChunk {
     pub source: String,
     pub chunk: String,
@@ -67,6 +92,7 @@ pub struct SemanticRetrieverChunk {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict", serde(deny_unknown_fields))]
 pub struct AttributionSourceId {
     pub grounding_passage: GroundingPassageId,
     pub semantic_retriever_chunk: SemanticRetrieverChunk,
@@ -74,6 +100,7 @@ pub struct AttributionSourceId {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict", serde(deny_unknown_fields))]
 pub struct GroundingAttribution {
     pub source_id: AttributionSourceId,
     pub content: Content,
@@ -81,6 +108,7 @@ pub struct GroundingAttribution {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict", serde(deny_unknown_fields))]
 pub struct Web {
     pub uri: String,
     pub title: String,
@@ -88,21 +116,27 @@ pub struct Web {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict", serde(deny_unknown_fields))]
 pub struct GroundingChunk {
     pub web: Web,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict", serde(deny_unknown_fields))]
 pub struct Segment {
+    #[serde(default)]
     pub part_index: i32,
+    #[serde(default)]
     pub start_index: i32,
     pub end_index: i32,
+    #[serde(default)]
     pub text: String,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict", serde(deny_unknown_fields))]
 pub struct GroundingSupport {
     #[serde(default)]
     pub grounding_chunk_indices: Vec<i32>,
@@ -113,6 +147,7 @@ pub struct GroundingSupport {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict", serde(deny_unknown_fields))]
 pub struct SearchEntryPoint {
     #[serde(default)]
     pub rendered_content: Option<String>,
@@ -120,8 +155,9 @@ pub struct SearchEntryPoint {
     pub sdk_blob: Option<String>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict", serde(deny_unknown_fields))]
 pub struct RetrievalMetadata {
     #[serde(default)]
     pub google_search_dynamic_retrieval_score: Option<f32>,
@@ -129,6 +165,7 @@ pub struct RetrievalMetadata {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict", serde(deny_unknown_fields))]
 pub struct GroundingMetadata {
     #[serde(default)]
     pub grounding_chunks: Vec<GroundingChunk>,
@@ -138,11 +175,14 @@ pub struct GroundingMetadata {
     pub web_search_queries: Vec<String>,
     #[serde(default)]
     pub search_entry_point: Option<SearchEntryPoint>,
+    /// Only reported for Google search retrieval, so empty for Google search.
+    #[serde(default)]
     pub retrieval_metadata: RetrievalMetadata,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename = "candidate")]
+#[cfg_attr(feature = "strict", serde(deny_unknown_fields))]
 pub struct LogCandidate {
     pub token: String,
     pub token_id: i32,
@@ -151,12 +191,14 @@ pub struct LogCandidate {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict", serde(deny_unknown_fields))]
 pub struct TopCandidates {
     pub candidates: LogCandidate,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict", serde(deny_unknown_fields))]
 pub struct LogprobsResult {
     #[serde(default)]
     pub top_candidates: Vec<TopCandidates>,
@@ -166,12 +208,14 @@ pub struct LogprobsResult {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict", serde(deny_unknown_fields))]
 pub struct UrlRetrievalContext {
     pub retrieved_url: String,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict", serde(deny_unknown_fields))]
 pub struct UrlRetrievalMetadata {
     #[serde(default)]
     pub url_retrieval_contexts: Vec<UrlRetrievalContext>,
@@ -179,7 +223,9 @@ pub struct UrlRetrievalMetadata {
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict", serde(deny_unknown_fields))]
 pub struct Candidate {
+    #[serde(default)]
     pub content: Content,
     #[serde(default)]
     pub finish_reason: Option<FinishReason>,
@@ -205,6 +251,7 @@ pub struct Candidate {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[non_exhaustive]
 pub enum BlockReason {
     BlockReasonUnspecified,
     Safety,
@@ -212,10 +259,14 @@ pub enum BlockReason {
     BlockList,
     ProhibitedContent,
     ImageSafety,
+    /// A block reason returned by the API which is not yet modeled.
+    #[serde(other)]
+    Unknown,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict", serde(deny_unknown_fields))]
 pub struct PromptFeedBack {
     #[serde(default)]
     pub block_reason: Option<BlockReason>,
@@ -225,13 +276,15 @@ pub struct PromptFeedBack {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict", serde(deny_unknown_fields))]
 pub struct ModalityTokenCount {
     pub modality: Modality,
     pub token_count: i32,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict", serde(deny_unknown_fields))]
 pub struct UsageMetadata {
     #[serde(default)]
     pub prompt_token_count: Option<i32>,
@@ -255,8 +308,88 @@ pub struct UsageMetadata {
     pub tool_use_prompt_tokens_details: Vec<ModalityTokenCount>,
 }
 
+impl UsageMetadata {
+    /// Usage with every count zero, as reported for responses without usage.
+    pub fn zeroed() -> Self {
+        UsageMetadata {
+            prompt_token_count: Some(0),
+            cached_content_token_count: Some(0),
+            candidates_token_count: Some(0),
+            tool_use_prompt_token_count: Some(0),
+            thoughts_token_count: Some(0),
+            total_token_count: Some(0),
+            ..Default::default()
+        }
+    }
+
+    /// Add the token counts of `other` to these counts, e.g. to total the usage of several
+    /// requests.  A count is `None` only if neither reported it.  The per modality details
+    /// are totalled by modality.
+    pub fn add(&mut self, other: &UsageMetadata) {
+        self.combine(other, |count, other| count + other);
+    }
+
+    /// Fold in the running totals reported by a later chunk of the same streamed response,
+    /// keeping the largest of each count, so a chunk which leaves out a count does not lose
+    /// it.
+    pub(crate) fn accumulate(&mut self, later: &UsageMetadata) {
+        self.combine(later, i32::max);
+    }
+
+    fn combine(&mut self, other: &UsageMetadata, op: impl Fn(i32, i32) -> i32) {
+        let count = |count: &mut Option<i32>, other: Option<i32>| {
+            if let Some(other) = other {
+                *count = Some(count.map_or(other, |count| op(count, other)));
+            }
+        };
+
+        count(&mut self.prompt_token_count, other.prompt_token_count);
+        count(
+            &mut self.cached_content_token_count,
+            other.cached_content_token_count,
+        );
+        count(
+            &mut self.candidates_token_count,
+            other.candidates_token_count,
+        );
+        count(
+            &mut self.tool_use_prompt_token_count,
+            other.tool_use_prompt_token_count,
+        );
+        count(&mut self.thoughts_token_count, other.thoughts_token_count);
+        count(&mut self.total_token_count, other.total_token_count);
+
+        let details = |details: &mut Vec<ModalityTokenCount>, other: &[ModalityTokenCount]| {
+            for other in other {
+                match details
+                    .iter_mut()
+                    .find(|detail| detail.modality == other.modality)
+                {
+                    Some(detail) => detail.token_count = op(detail.token_count, other.token_count),
+                    None => details.push(other.clone()),
+                }
+            }
+        };
+
+        details(
+            &mut self.prompt_tokens_details,
+            &other.prompt_tokens_details,
+        );
+        details(&mut self.cache_tokens_details, &other.cache_tokens_details);
+        details(
+            &mut self.candidates_tokens_details,
+            &other.candidates_tokens_details,
+        );
+        details(
+            &mut self.tool_use_prompt_tokens_details,
+            &other.tool_use_prompt_tokens_details,
+        );
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict", serde(deny_unknown_fields))]
 pub struct ContentResponse {
     #[serde(default)]
     pub candidates: Vec<Candidate>,
@@ -269,3 +402,52 @@ pub struct ContentResponse {
     #[serde(default)]
     pub error: Option<Value>,
 }
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "strict", serde(deny_unknown_fields))]
+pub struct CountTokensResponse {
+    #[serde(default)]
+    pub total_tokens: Option<i32>,
+    #[serde(default)]
+    pub cached_content_token_count: Option<i32>,
+    #[serde(default)]
+    pub prompt_tokens_details: Vec<ModalityTokenCount>,
+    #[serde(default)]
+    pub error: Option<Value>,
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use crate::google::common::HarmProbability;
+
+    use super::{BlockReason, ContentResponse, FinishReason};
+
+    #[test]
+    fn unknown_enum_values() {
+        assert!(matches!(
+            serde_json::from_value::<FinishReason>(json!("NOT_A_FINISH_REASON")),
+            Ok(FinishReason::Unknown)
+        ));
+        assert!(matches!(
+            serde_json::from_value::<BlockReason>(json!("NOT_A_BLOCK_REASON")),
+            Ok(BlockReason::Unknown)
+        ));
+        assert!(matches!(
+            serde_json::from_value::<HarmProbability>(json!("NOT_A_PROBABILITY")),
+            Ok(HarmProbability::Unknown)
+        ));
+    }
+
+    #[test]
+    fn unknown_fields() {
+        let response = serde_json::from_value::<ContentResponse>(json!({
+            "candidates": [],
+            "notAField": true
+        }));
+
+        assert_eq!(response.is_err(), cfg!(feature = "strict"));
+    }
+}
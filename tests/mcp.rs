@@ -82,7 +82,7 @@ pub struct SayHiddenTool {}
 
 impl SayHiddenTool {
     pub fn call_tool(&self) -> Result<CallToolResult, CallToolError> {
-        let message = format!("{}", SECRET);
+        let message = SECRET.to_string();
         Ok(CallToolResult::text_content(vec![TextContent::from(
             message,
         )]))
@@ -188,9 +188,10 @@ async fn gemini_client() -> Result<Client, Error> {
 
     let key = env::var(GEMINI_API_ENV_KEY)?;
 
-    Ok(Client::new(&"gemini-2.0-flash".try_into()?, &key)
-        .await?
-        .with_defaults())
+    let mut client = Client::new(&"gemini-2.0-flash".try_into()?, &key).await?;
+    client.with_defaults();
+
+    Ok(client)
 }
 
 async fn mcp_server() -> Result<
@@ -265,9 +266,8 @@ async fn test_mcp() -> Result<(), Error> {
 
     weather_client.clone().start().await?;
 
-    let mut g_client = gemini_client()
-        .await?
-        .with_defaults()
+    let mut g_client = gemini_client().await?;
+    g_client
         .with_tools_client(vec![weather_client.clone(), weather_client.clone()])
         .await?;
 
@@ -313,7 +313,7 @@ async fn test_mcp() -> Result<(), Error> {
             .contains(&format!("{}_foobarbaz_3", SECRET3))
     );
 
-    weather_client.shut_down().await?;
+    g_client.shutdown_tools().await?;
     server.graceful_shutdown(Some(Duration::from_secs(30)));
 
     Ok(())